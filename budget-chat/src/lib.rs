@@ -1,15 +1,294 @@
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::fmt::{Debug, Formatter};
 use std::future::{pending};
 use std::io;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use common::{send, tick, ConnectionPermit, Listener};
 use futures::{FutureExt, StreamExt};
 use futures::stream::FuturesUnordered;
-use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, Lines, ReadBuf, ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, UnixListener, UnixStream};
 use tokio::select;
+use tokio::task::AbortHandle;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{interval, Instant};
 use tracing::{error, info, warn};
 
+#[derive(Debug, Clone)]
+pub struct ChatConfig {
+    /// Terminate server-generated lines with `\r\n` instead of `\n`
+    pub crlf: bool,
+    /// Tell joining users who else is in the room; when off they just get `* welcome`
+    pub reveal_roster: bool,
+    /// Lines that may be waiting to be written to a client before it's considered too slow
+    /// and disconnected
+    pub send_queue_capacity: usize,
+    /// Prefix every broadcast with an increasing sequence number, e.g. `#42 [alice] hi`
+    pub sequence_numbers: bool,
+    /// Turn away new connections while this many clients are connected, including any still
+    /// choosing a nick
+    pub max_clients: Option<usize>,
+    /// Disconnect clients that leave a line unfinished while sending it too slowly
+    pub min_throughput: Option<MinThroughput>,
+    /// Sent to each client on connect, asking for a nick
+    pub prompt: String,
+    /// Sent to each client right after it joins, following the room list
+    pub motd: Option<String>,
+    /// Nicks nobody may take, e.g. `server`, so users can't pass themselves off as the system.
+    /// Compared ignoring case.
+    pub reserved_nicks: HashSet<String>,
+    /// Cut messages longer than this many characters short, marking them with `...`, before
+    /// passing them on
+    pub max_message_len: Option<usize>,
+    /// Called with each user's nick as they join the room
+    pub on_join: Option<NickCallback>,
+    /// Called with each user's nick as they leave the room, however they go: quitting, being
+    /// dropped for sending too slowly, or falling too far behind on what's sent to them
+    pub on_leave: Option<NickCallback>,
+    /// Limit how fast clients may send messages, muting those who keep going over
+    pub flood_control: Option<FloodControl>,
+}
+
+/// A hook run as users come and go. It's called from the room's task, so it should be quick.
+#[derive(Clone)]
+pub struct NickCallback(pub Arc<dyn Fn(&str) + Send + Sync>);
+
+impl Debug for NickCallback {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("NickCallback")
+    }
+}
+
+fn notify(callback: &Option<NickCallback>, nick: &str) {
+    if let Some(NickCallback(ref callback)) = callback {
+        callback(nick);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MinThroughput {
+    /// Bytes a client with a partly-sent line must send in each window
+    pub bytes: u64,
+    pub window: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct FloodControl {
+    /// Messages a client may send in each window; any more are dropped, and count as a strike
+    pub messages: u32,
+    pub window: Duration,
+    /// Strikes within `strike_window` that get a client muted
+    pub strikes: usize,
+    pub strike_window: Duration,
+    /// How long a muted client's messages are all dropped
+    pub mute: Duration,
+}
+
+/// Where a client stands against `FloodControl`
+#[derive(Debug)]
+struct FloodState {
+    /// When the current window began, and how many messages have been sent in it
+    window: (Instant, u32),
+    /// When recent strikes happened, oldest first
+    strikes: VecDeque<Instant>,
+    muted_until: Option<Instant>,
+}
+
+/// Why a message wasn't passed on
+#[derive(Debug, Eq, PartialEq)]
+enum Flooding {
+    TooFast,
+    Muted,
+}
+
+impl FloodState {
+    fn new() -> FloodState {
+        FloodState { window: (Instant::now(), 0), strikes: VecDeque::new(), muted_until: None }
+    }
+
+    /// Count a message, returning why it should be dropped if it should
+    fn check(&mut self, control: &FloodControl) -> Result<(), Flooding> {
+        let now = Instant::now();
+        if let Some(until) = self.muted_until {
+            if now < until {
+                return Err(Flooding::Muted);
+            }
+            // A fresh start, rather than the tail of the window that got it muted
+            self.muted_until = None;
+            self.window = (now, 0);
+        }
+        if now.duration_since(self.window.0) >= control.window {
+            self.window = (now, 0);
+        }
+        self.window.1 += 1;
+        if self.window.1 <= control.messages {
+            return Ok(());
+        }
+        while self.strikes.front().is_some_and(|&at| now.duration_since(at) >= control.strike_window) {
+            self.strikes.pop_front();
+        }
+        self.strikes.push_back(now);
+        if self.strikes.len() >= control.strikes {
+            self.strikes.clear();
+            self.muted_until = Some(now + control.mute);
+            return Err(Flooding::Muted);
+        }
+        Err(Flooding::TooFast)
+    }
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        ChatConfig {
+            crlf: false,
+            reveal_roster: true,
+            send_queue_capacity: 128,
+            sequence_numbers: false,
+            max_clients: None,
+            min_throughput: None,
+            prompt: "enter nick".to_string(),
+            motd: None,
+            reserved_nicks: HashSet::new(),
+            max_message_len: None,
+            on_join: None,
+            on_leave: None,
+            flood_control: None,
+        }
+    }
+}
+
+impl ChatConfig {
+    fn line(&self, text: &str) -> String {
+        let newline = if self.crlf { "\r\n" } else { "\n" };
+        format!("{text}{newline}")
+    }
+
+    /// Format a line that's going to everyone, numbering it if configured
+    fn broadcast_line(&self, sequence: &mut u64, text: &str) -> String {
+        if self.sequence_numbers {
+            *sequence += 1;
+            self.line(&format!("#{sequence} {text}"))
+        } else {
+            self.line(text)
+        }
+    }
+
+    /// Shorten a message to `max_message_len`, if it's set and the message is longer
+    fn truncate<'a>(&self, message: &'a str) -> Cow<'a, str> {
+        match self.max_message_len.and_then(|max| message.char_indices().nth(max)) {
+            Some((end, _)) => Cow::Owned(format!("{}...", &message[..end])),
+            None => Cow::Borrowed(message),
+        }
+    }
+
+    fn is_reserved_nick(&self, nick: &str) -> bool {
+        self.reserved_nicks.iter().any(|r| r.eq_ignore_ascii_case(nick))
+    }
+}
+
+struct Metrics {
+    sent_total: AtomicU64,
+    dropped_total: AtomicU64,
+}
+
+static METRICS: Metrics = Metrics {
+    sent_total: AtomicU64::new(0),
+    dropped_total: AtomicU64::new(0),
+};
+
+/// Every client's counters, for as long as the client or its writer task is around
+static CLIENTS: Mutex<Vec<Weak<SendCounters>>> = Mutex::new(Vec::new());
+
+/// A client's send queue figures, shared between the room and the client's writer task
+#[derive(Debug, Default)]
+struct SendCounters {
+    nick: Mutex<Option<String>>,
+    /// Lines queued or being written, but not yet written
+    queued: AtomicUsize,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl SendCounters {
+    fn register() -> Arc<SendCounters> {
+        let counters = Arc::new(SendCounters::default());
+        let mut clients = CLIENTS.lock().unwrap();
+        clients.retain(|c| c.strong_count() > 0);
+        clients.push(Arc::downgrade(&counters));
+        counters
+    }
+}
+
+/// Send queue figures for the whole room
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Lines written to clients since startup
+    pub sent_total: u64,
+    /// Lines that couldn't be queued, because a client's queue was full or its socket had failed
+    pub dropped_total: u64,
+    /// Lines waiting in the fullest client send queue right now
+    pub slowest_queue_depth: usize,
+}
+
+pub fn metrics() -> MetricsSnapshot {
+    MetricsSnapshot {
+        sent_total: METRICS.sent_total.load(Ordering::Relaxed),
+        dropped_total: METRICS.dropped_total.load(Ordering::Relaxed),
+        slowest_queue_depth: client_metrics().iter().map(|c| c.queued).max().unwrap_or(0),
+    }
+}
+
+/// Send queue figures for one client
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ClientMetrics {
+    /// `None` until the client has picked a nick
+    pub nick: Option<String>,
+    /// Lines waiting to be written, including any part-written
+    pub queued: usize,
+    /// Lines written to the client
+    pub sent: u64,
+    /// Lines that couldn't be queued. The first one gets the client disconnected, so this is
+    /// rarely more than 1; `MetricsSnapshot::dropped_total` shows how often it happens.
+    pub dropped: u64,
+}
+
+/// Send queue figures for each client, including any that have left but are still having
+/// their last lines written
+pub fn client_metrics() -> Vec<ClientMetrics> {
+    CLIENTS.lock().unwrap().iter()
+        .filter_map(Weak::upgrade)
+        .map(|c| ClientMetrics {
+            nick: c.nick.lock().unwrap().clone(),
+            queued: c.queued.load(Ordering::Relaxed),
+            sent: c.sent.load(Ordering::Relaxed),
+            dropped: c.dropped.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
 fn is_valid_nick(s: &str) -> bool {
-    s.chars().all(|c| c.is_ascii_alphanumeric())
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn nick_in_use<C: AsyncRead + AsyncWrite>(clients: &[ChatClient<C>], nick: &str) -> bool {
+    clients.iter().any(|c| c.nick.as_deref() == Some(nick))
+}
+
+/// Queue a line for every joined client, except the one at index `except` if given
+fn broadcast<C: AsyncRead + AsyncWrite + Send + 'static>(clients: &mut [ChatClient<C>], except: Option<usize>, message: &str) {
+    for (i, c) in clients.iter_mut().enumerate() {
+        if Some(i) != except && c.state == ClientState::Connected {
+            c.send_or_die(message);
+        }
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -19,38 +298,129 @@ enum ClientState {
     Disconnected
 }
 
+/// Counts the bytes read through it, and how many of them came after the last newline
+#[derive(Debug)]
+struct MeteredReader<R> {
+    inner: R,
+    bytes: u64,
+    partial: usize,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for MeteredReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let read = &buf.filled()[before..];
+        this.bytes += read.len() as u64;
+        this.partial = match read.iter().rposition(|&c| c == b'\n') {
+            Some(newline) => read.len() - newline - 1,
+            None => this.partial + read.len(),
+        };
+        result
+    }
+}
+
 #[derive(Debug)]
 struct ChatClient<C: AsyncRead + AsyncWrite> {
-    reader: Lines<BufReader<ReadHalf<C>>>,
-    writer: WriteHalf<C>,
+    reader: Lines<BufReader<MeteredReader<ReadHalf<C>>>>,
+    /// When the current throughput window began, and how many bytes had been read by then
+    window: (Instant, u64),
+    outbox: mpsc::Sender<String>,
+    counters: Arc<SendCounters>,
     state: ClientState,
+    /// Whether the room was told this client entered, and so must be told when it leaves
+    joined: bool,
     nick: Option<String>,
+    /// Set by `/away`, with the reason given, if any, and cleared by `/back`
+    away: Option<String>,
+    /// The task writing queued lines to the socket, aborted if the client stops reading
+    writer: AbortHandle,
+    flood: FloodState,
 }
 
-impl<C: AsyncRead + AsyncWrite> ChatClient<C> {
-    fn new(_addr: SocketAddr, stream: C) -> ChatClient<C> {
+impl<C: AsyncRead + AsyncWrite + Send + 'static> ChatClient<C> {
+    /// `permit` is held by the writer task, so it counts against the source IP's connection
+    /// limit until the socket is actually closed
+    fn new(stream: C, send_queue_capacity: usize, permit: Option<ConnectionPermit>) -> ChatClient<C> {
         let (r, w) = tokio::io::split(stream);
-        let reader = BufReader::new(r).lines();
+        let reader = BufReader::new(MeteredReader { inner: r, bytes: 0, partial: 0 }).lines();
+        let (outbox, queued) = mpsc::channel(send_queue_capacity.max(1));
+        let counters = SendCounters::register();
+        let writer = tokio::spawn(write_queued(w, queued, counters.clone(), permit)).abort_handle();
         ChatClient {
             reader,
-            writer: w,
+            window: (Instant::now(), 0),
+            outbox,
+            counters,
             state: ClientState::AwaitingNick,
+            joined: false,
             nick: None,
+            away: None,
+            writer,
+            flood: FloodState::new(),
+        }
+    }
+
+    /// At the end of each throughput window, whether the client is part way through a line but
+    /// sent less than the minimum during the window
+    fn too_slow(&mut self, min: &MinThroughput) -> bool {
+        let (started, bytes_then) = self.window;
+        if started.elapsed() < min.window {
+            return false;
         }
+        let metered = self.reader.get_ref().get_ref();
+        let slow = metered.partial > 0 && metered.bytes - bytes_then < min.bytes;
+        self.window = (Instant::now(), metered.bytes);
+        slow
+    }
+
+    fn set_nick(&mut self, nick: &str) {
+        self.nick = Some(nick.to_string());
+        *self.counters.nick.lock().unwrap() = self.nick.clone();
     }
 
-    async fn write_or_die(&mut self, message: &str) {
-        match self.writer.write_all(message.as_bytes()).await {
-            Ok(_) => {
+    /// Queue a line for this client's writer task, without waiting for it to be written
+    fn send_or_die(&mut self, message: &str) {
+        // Counted before it's queued, so the writer never finds it uncounted
+        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+        let result = self.outbox.try_send(message.to_string());
+        if result.is_err() {
+            self.counters.queued.fetch_sub(1, Ordering::Relaxed);
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            METRICS.dropped_total.fetch_add(1, Ordering::Relaxed);
+        }
+        match result {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                error!(client=?self.nick, "send queue full, closing");
+                // The writer is stuck behind a peer that isn't reading, and would hold the
+                // socket open forever
+                self.writer.abort();
+                self.state = ClientState::Disconnected;
             }
-            Err(e) => {
-                error!(error=?e, "write failed, closing");
+            Err(TrySendError::Closed(_)) => {
+                error!(client=?self.nick, "write failed, closing");
                 self.state = ClientState::Disconnected;
             }
         }
     }
 }
 
+/// Drain a client's send queue into its socket, so a slow reader only holds up itself.
+/// Stops when the socket fails or the client is dropped and everything queued is written, or
+/// when it's aborted.
+async fn write_queued<W: AsyncWrite>(mut writer: WriteHalf<W>, mut queued: mpsc::Receiver<String>, counters: Arc<SendCounters>, _permit: Option<ConnectionPermit>) {
+    while let Some(message) = queued.recv().await {
+        if send(&mut writer, message.as_bytes()).await.is_err() {
+            break;
+        }
+        counters.queued.fetch_sub(1, Ordering::Relaxed);
+        counters.sent.fetch_add(1, Ordering::Relaxed);
+        METRICS.sent_total.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 async fn next_message<C: AsyncRead + AsyncWrite>(clients: &mut [ChatClient<C>]) -> (usize, Result<Option<String>, io::Error>) {
     let mut futures: FuturesUnordered<_> = clients
         .iter_mut()
@@ -63,21 +433,112 @@ async fn next_message<C: AsyncRead + AsyncWrite>(clients: &mut [ChatClient<C>])
     }
 }
 
-pub async fn serve(address: SocketAddr) -> io::Result<()> {
-    let mut clients: Vec<ChatClient<TcpStream>> = Vec::new();
+/// Anything the chat room can accept client streams from
+trait ChatListener {
+    type Stream: AsyncRead + AsyncWrite + Debug + Send + 'static;
+    type Addr: Debug;
+
+    /// Accept a client, with the permit to keep for as long as it's connected, if any
+    async fn accept(&self) -> io::Result<(Self::Stream, Self::Addr, Option<ConnectionPermit>)>;
+
+    /// Whether a connection just accepted should be logged
+    fn log_accept(&self) -> bool {
+        true
+    }
+}
+
+impl ChatListener for Listener {
+    type Stream = TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&self) -> io::Result<(TcpStream, SocketAddr, Option<ConnectionPermit>)> {
+        let (stream, addr, permit) = Listener::accept(self).await?;
+        Ok((stream, addr, Some(permit)))
+    }
+
+    fn log_accept(&self) -> bool {
+        Listener::log_accept(self)
+    }
+}
+
+impl ChatListener for UnixListener {
+    type Stream = UnixStream;
+    type Addr = tokio::net::unix::SocketAddr;
+
+    async fn accept(&self) -> io::Result<(UnixStream, tokio::net::unix::SocketAddr, Option<ConnectionPermit>)> {
+        let (stream, addr) = UnixListener::accept(self).await?;
+        Ok((stream, addr, None))
+    }
+}
+
+pub async fn serve(listener: Listener, config: ChatConfig) -> io::Result<()> {
     info!("starting");
-    let listener = TcpListener::bind(address).await?;
+    run(listener, config).await
+}
+
+/// Serve the chat room on a Unix domain socket, e.g. for local testing
+pub async fn serve_unix(path: impl AsRef<Path>, config: ChatConfig) -> io::Result<()> {
+    info!(path=?path.as_ref(), "starting");
+    let listener = UnixListener::bind(path)?;
+    run(listener, config).await
+}
+
+async fn run<L: ChatListener>(listener: L, config: ChatConfig) -> io::Result<()> {
+    let mut clients: Vec<ChatClient<L::Stream>> = Vec::new();
+    let mut sequence: u64 = 0;
+    let mut throughput_check = config.min_throughput.as_ref().map(|min| interval(min.window));
     loop {
-        clients.retain(|c| c.state != ClientState::Disconnected);
+        // Telling the room someone left can drop more clients who can't keep up, who then
+        // need announcing in turn
+        loop {
+            let mut left = Vec::new();
+            for c in clients.iter().filter(|c| c.state == ClientState::Disconnected) {
+                info!(client=?c.nick, sent=c.counters.sent.load(Ordering::Relaxed), dropped=c.counters.dropped.load(Ordering::Relaxed),
+                    queued=c.counters.queued.load(Ordering::Relaxed), "removing client");
+                if c.joined {
+                    left.push(c.nick.clone().expect("joined without nick"));
+                }
+            }
+            clients.retain(|c| c.state != ClientState::Disconnected);
+            if left.is_empty() {
+                break;
+            }
+            for nick in left {
+                notify(&config.on_leave, &nick);
+                let line = config.broadcast_line(&mut sequence, &format!("* {} left", nick));
+                broadcast(&mut clients, None, &line);
+            }
+        }
 
         let new_client = select! {
+            _ = tick(&mut throughput_check) => {
+                let min = config.min_throughput.as_ref().expect("checking throughput without a minimum");
+                for c in clients.iter_mut() {
+                    if c.state == ClientState::Disconnected || !c.too_slow(min) {
+                        continue;
+                    }
+                    warn!(client=?c.nick, min_bytes=min.bytes, window=?min.window, "sending too slowly, disconnecting");
+                    c.state = ClientState::Disconnected;
+                }
+                None
+            }
+
             incoming = listener.accept() => {
                 match incoming {
-                    Ok((stream, addr)) => {
-                        info!(client=%addr, "connection received");
-                        let mut client = ChatClient::new(addr, stream);
-                        client.write_or_die("enter nick\n").await;
-                        Some(client)
+                    Ok((stream, addr, permit)) => {
+                        if listener.log_accept() {
+                            info!(client=?addr, "connection received");
+                        }
+                        let mut client = ChatClient::new(stream, config.send_queue_capacity, permit);
+                        if config.max_clients.is_some_and(|max| clients.len() >= max) {
+                            warn!(client=?addr, clients=clients.len(), "server full, turning away");
+                            // Dropping the client closes the connection once this is written
+                            client.send_or_die(&config.line("server full"));
+                            None
+                        } else {
+                            client.send_or_die(&config.line(&config.prompt));
+                            Some(client)
+                        }
                     }
 
                     Err(e) => {
@@ -94,31 +555,98 @@ pub async fn serve(address: SocketAddr) -> io::Result<()> {
                         match clients[client_idx].state {
                             ClientState::AwaitingNick => {
                                 let n = m.as_str().trim();
-                                if is_valid_nick(n) {
+                                if nick_in_use(&clients, n) {
+                                    warn!(nick=n, client=?clients[client_idx], "nick in use");
+                                    clients[client_idx].send_or_die(&config.line("nick in use"));
+                                    clients[client_idx].state = ClientState::Disconnected;
+                                } else if config.is_reserved_nick(n) {
+                                    warn!(nick=n, client=?clients[client_idx], "nick reserved");
+                                    clients[client_idx].send_or_die(&config.line("nick reserved"));
+                                    clients[client_idx].state = ClientState::Disconnected;
+                                } else if is_valid_nick(n) {
                                     info!(nick=n, client=?clients[client_idx], "set nick");
-                                    let in_room = format!("* in room: {}\n",
-                                        clients.iter().filter_map(|i| i.nick.as_deref()).collect::<Vec<&str>>().join(", "));
-                                    clients[client_idx].nick = Some(n.to_string());
+                                    let in_room = if config.reveal_roster {
+                                        config.line(&format!("* in room: {}",
+                                            clients.iter().filter_map(|i| i.nick.as_deref()).collect::<Vec<&str>>().join(", ")))
+                                    } else {
+                                        config.line("* welcome")
+                                    };
+                                    clients[client_idx].set_nick(n);
                                     clients[client_idx].state = ClientState::Connected;
-                                    clients[client_idx].write_or_die(in_room.as_str()).await;
-
-                                    let entered = format!("* {} entered\n", n);
-                                    for (i, c) in clients.iter_mut().enumerate() {
-                                        if i != client_idx && c.state == ClientState::Connected {
-                                            c.write_or_die(entered.as_str()).await;
-                                        }
+                                    clients[client_idx].joined = true;
+                                    clients[client_idx].send_or_die(in_room.as_str());
+                                    if let Some(ref motd) = config.motd {
+                                        clients[client_idx].send_or_die(&config.line(motd));
                                     }
+
+                                    notify(&config.on_join, n);
+                                    let entered = config.broadcast_line(&mut sequence, &format!("* {} entered", n));
+                                    broadcast(&mut clients, Some(client_idx), &entered);
                                 } else {
                                     warn!(nick=n, client=?clients[client_idx], "invalid nick");
-                                    clients[client_idx].write_or_die("invalid nick\n").await;
+                                    clients[client_idx].send_or_die(&config.line("invalid nick"));
                                     clients[client_idx].state = ClientState::Disconnected;
                                 }
                             }
                             ClientState::Connected => {
-                                let said = format!("[{}] {}\n", clients[client_idx].nick.as_ref().expect("connected without nick"), m);
-                                for (i, c) in clients.iter_mut().enumerate() {
-                                    if i != client_idx && c.state == ClientState::Connected {
-                                        c.write_or_die(said.as_str()).await;
+                                let nick = clients[client_idx].nick.clone().expect("connected without nick");
+                                if let Some(new_nick) = m.strip_prefix("/nick ") {
+                                    let new_nick = new_nick.trim();
+                                    if !is_valid_nick(new_nick) {
+                                        warn!(nick=nick, new_nick=new_nick, "invalid nick change");
+                                        clients[client_idx].send_or_die(&config.line("* invalid nick"));
+                                    } else if nick_in_use(&clients, new_nick) {
+                                        warn!(nick=nick, new_nick=new_nick, "nick change to nick in use");
+                                        clients[client_idx].send_or_die(&config.line("* nick in use"));
+                                    } else if config.is_reserved_nick(new_nick) {
+                                        warn!(nick=nick, new_nick=new_nick, "nick change to reserved nick");
+                                        clients[client_idx].send_or_die(&config.line("* nick reserved"));
+                                    } else {
+                                        info!(nick=nick, new_nick=new_nick, "changed nick");
+                                        clients[client_idx].set_nick(new_nick);
+                                        let renamed = config.broadcast_line(&mut sequence, &format!("* {} is now known as {}", nick, new_nick));
+                                        broadcast(&mut clients, None, &renamed);
+                                    }
+                                } else if let Some(reason) = m.strip_prefix("/away").filter(|r| r.is_empty() || r.starts_with(' ')) {
+                                    let reason = reason.trim();
+                                    info!(nick=nick, reason=reason, "away");
+                                    clients[client_idx].away = Some(reason.to_string());
+                                    clients[client_idx].send_or_die(&config.line("* you are away"));
+                                } else if m.trim() == "/back" {
+                                    info!(nick=nick, "back");
+                                    clients[client_idx].away = None;
+                                    clients[client_idx].send_or_die(&config.line("* you are back"));
+                                } else if m.trim() == "/who" {
+                                    let who = clients.iter()
+                                        .filter(|c| c.state == ClientState::Connected)
+                                        .filter_map(|c| {
+                                            let nick = c.nick.as_deref()?;
+                                            Some(match c.away.as_deref() {
+                                                None => nick.to_string(),
+                                                Some("") => format!("{nick} (away)"),
+                                                Some(reason) => format!("{nick} (away: {reason})"),
+                                            })
+                                        })
+                                        .collect::<Vec<String>>();
+                                    clients[client_idx].send_or_die(&config.line(&format!("* who: {}", who.join(", "))));
+                                } else {
+                                    let flooding = match config.flood_control {
+                                        Some(ref control) => clients[client_idx].flood.check(control),
+                                        None => Ok(()),
+                                    };
+                                    match flooding {
+                                        Ok(()) => {
+                                            let said = config.broadcast_line(&mut sequence, &format!("[{}] {}", nick, config.truncate(m)));
+                                            broadcast(&mut clients, Some(client_idx), &said);
+                                        }
+                                        Err(Flooding::TooFast) => {
+                                            warn!(nick=nick, "sending too fast, dropping message");
+                                            clients[client_idx].send_or_die(&config.line("* slow down"));
+                                        }
+                                        Err(Flooding::Muted) => {
+                                            warn!(nick=nick, until=?clients[client_idx].flood.muted_until, "muted, dropping message");
+                                            clients[client_idx].send_or_die(&config.line("* you are muted"));
+                                        }
                                     }
                                 }
                             }
@@ -127,13 +655,9 @@ pub async fn serve(address: SocketAddr) -> io::Result<()> {
                     }
                     Ok(None) | Err(_) => {
                         warn!(error=?message, "Client disconnect");
-                        if clients[client_idx].state == ClientState::Connected {
-                            let left = format!("* {} left\n", clients[client_idx].nick.as_ref().expect("connected without nick"));
-                            for (i, c) in clients.iter_mut().enumerate() {
-                                if i != client_idx {
-                                    c.write_or_die(left.as_str()).await;
-                                }
-                            }
+                        if clients[client_idx].state == ClientState::AwaitingNick {
+                            // Nobody was told this client arrived, so nobody is told it left
+                            info!("client left before choosing a nick");
                         }
                         clients[client_idx].state = ClientState::Disconnected;
                     }
@@ -147,3 +671,381 @@ pub async fn serve(address: SocketAddr) -> io::Result<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
+    use tokio::time::timeout;
+
+    /// Hands the room whatever streams a test connects
+    struct TestListener(tokio::sync::Mutex<mpsc::Receiver<DuplexStream>>);
+
+    impl ChatListener for TestListener {
+        type Stream = DuplexStream;
+        type Addr = ();
+
+        async fn accept(&self) -> io::Result<(DuplexStream, (), Option<ConnectionPermit>)> {
+            match self.0.lock().await.recv().await {
+                Some(stream) => Ok((stream, (), None)),
+                None => pending().await,
+            }
+        }
+    }
+
+    fn room(config: ChatConfig) -> mpsc::Sender<DuplexStream> {
+        let (connect, accepted) = mpsc::channel(8);
+        tokio::spawn(run(TestListener(tokio::sync::Mutex::new(accepted)), config));
+        connect
+    }
+
+    struct TestClient<S = DuplexStream> {
+        lines: Lines<BufReader<ReadHalf<S>>>,
+        writer: WriteHalf<S>,
+    }
+
+    impl TestClient {
+        /// Connect with `buffer` bytes between us and the room
+        async fn connect(room: &mpsc::Sender<DuplexStream>, buffer: usize) -> TestClient {
+            let (ours, theirs) = duplex(buffer);
+            room.send(theirs).await.unwrap();
+            TestClient::new(ours)
+        }
+
+        /// Connect, and join as `nick`
+        async fn join(room: &mpsc::Sender<DuplexStream>, nick: &str, buffer: usize) -> TestClient {
+            let mut client = TestClient::connect(room, buffer).await;
+            client.login(nick).await;
+            client
+        }
+    }
+
+    impl<S: AsyncRead + AsyncWrite> TestClient<S> {
+        fn new(stream: S) -> TestClient<S> {
+            let (r, writer) = tokio::io::split(stream);
+            TestClient { lines: BufReader::new(r).lines(), writer }
+        }
+
+        /// Answer the nick prompt, and check we got in
+        async fn login(&mut self, nick: &str) {
+            assert_eq!(self.line().await.as_deref(), Some("enter nick"));
+            self.say(nick).await;
+            assert!(self.line().await.unwrap().starts_with("* in room"));
+        }
+
+        async fn say(&mut self, message: &str) {
+            self.writer.write_all(format!("{message}\n").as_bytes()).await.unwrap();
+        }
+
+        async fn line(&mut self) -> Option<String> {
+            timeout(Duration::from_secs(5), self.lines.next_line()).await.expect("timed out").unwrap()
+        }
+    }
+
+    /// An `on_leave` callback, and the nicks it's been called with
+    fn record_leaves() -> (NickCallback, Arc<std::sync::Mutex<Vec<String>>>) {
+        let left = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = left.clone();
+        (NickCallback(Arc::new(move |nick| recorder.lock().unwrap().push(nick.to_string()))), left)
+    }
+
+    #[tokio::test]
+    async fn stalled_client_does_not_block_others() {
+        let (on_leave, left) = record_leaves();
+        let room = room(ChatConfig { send_queue_capacity: 4, on_leave: Some(on_leave), ..ChatConfig::default() });
+        // Alice never reads again, and her socket buffer fills after a line or so
+        let alice = TestClient::join(&room, "alice", 16).await;
+        let mut bob = TestClient::join(&room, "bob", 4096).await;
+        let mut carol = TestClient::join(&room, "carol", 4096).await;
+        assert_eq!(bob.line().await.as_deref(), Some("* carol entered"));
+
+        let mut announced = 0;
+        for i in 0..50 {
+            bob.say(&format!("msg {i}")).await;
+            let mut line = carol.line().await;
+            if line.as_deref() == Some("* alice left") {
+                announced += 1;
+                line = carol.line().await;
+            }
+            assert_eq!(line, Some(format!("[bob] msg {i}")));
+        }
+        assert_eq!(announced, 1);
+        assert_eq!(*left.lock().unwrap(), ["alice"]);
+
+        // Alice was dropped, and her socket closed rather than left with a writer stuck on it
+        let mut alice = alice.writer;
+        timeout(Duration::from_secs(5), async {
+            while alice.write_all(b"still there?\n").await.is_ok() {}
+        }).await.expect("socket left open");
+    }
+
+    #[tokio::test]
+    async fn leaving_is_announced_once() {
+        let (on_leave, left) = record_leaves();
+        let room = room(ChatConfig { on_leave: Some(on_leave), ..ChatConfig::default() });
+        let mut alice = TestClient::join(&room, "alice", 4096).await;
+        let bob = TestClient::join(&room, "bob", 4096).await;
+        assert_eq!(alice.line().await.as_deref(), Some("* bob entered"));
+
+        drop(bob);
+        assert_eq!(alice.line().await.as_deref(), Some("* bob left"));
+        alice.say("anyone?").await;
+        let _carol = TestClient::join(&room, "carol", 4096).await;
+        assert_eq!(alice.line().await.as_deref(), Some("* carol entered"));
+        assert_eq!(*left.lock().unwrap(), ["bob"]);
+    }
+
+    #[tokio::test]
+    async fn stalled_client_queue_depth_rises() {
+        // Metrics are shared with every other test's room, so use nicks nobody else does
+        let metrics_for = |nick: &str| client_metrics().into_iter().find(|c| c.nick.as_deref() == Some(nick)).unwrap();
+        let room = room(ChatConfig { send_queue_capacity: 64, ..ChatConfig::default() });
+        // Too small for even one line of chat, so everything's left queued
+        let _stalled = TestClient::join(&room, "stalledqueue", 16).await;
+        let mut talker = TestClient::join(&room, "talkerqueue", 4096).await;
+
+        for i in 0..20 {
+            talker.say(&format!("msg {i}")).await;
+        }
+        timeout(Duration::from_secs(5), async {
+            while metrics_for("stalledqueue").queued < 21 {
+                tokio::task::yield_now().await;
+            }
+        }).await.expect("queue depth didn't rise");
+        assert!(metrics().slowest_queue_depth >= 21);
+        let stalled = metrics_for("stalledqueue");
+        assert_eq!(stalled.dropped, 0);
+        assert_eq!(stalled.sent, 2);
+        assert_eq!(metrics_for("talkerqueue").queued, 0);
+    }
+
+    #[tokio::test]
+    async fn unix_socket_room() {
+        let path = std::env::temp_dir().join(format!("budget-chat-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        tokio::spawn(serve_unix(path.clone(), ChatConfig::default()));
+        let connect = || async {
+            for _ in 0..100 {
+                if let Ok(stream) = UnixStream::connect(&path).await {
+                    return TestClient::new(stream);
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("room never started");
+        };
+        let mut alice = connect().await;
+        alice.login("alice").await;
+        let mut bob = connect().await;
+        bob.login("bob").await;
+        assert_eq!(alice.line().await.as_deref(), Some("* bob entered"));
+        bob.say("hello").await;
+        assert_eq!(alice.line().await.as_deref(), Some("[bob] hello"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn line_terminators() {
+        for (crlf, terminator) in [(false, &b"\n"[..]), (true, b"\r\n")] {
+            let room = room(ChatConfig { crlf, ..ChatConfig::default() });
+            let mut client = TestClient::connect(&room, 4096).await;
+            let mut prompt = Vec::new();
+            timeout(Duration::from_secs(5), client.lines.get_mut().read_until(b'\n', &mut prompt)).await.unwrap().unwrap();
+            assert_eq!(prompt, [&b"enter nick"[..], terminator].concat());
+        }
+    }
+
+    #[tokio::test]
+    async fn roster_on_join() {
+        let room_revealing = room(ChatConfig::default());
+        let _alice = TestClient::join(&room_revealing, "alice", 4096).await;
+        let mut bob = TestClient::connect(&room_revealing, 4096).await;
+        bob.line().await;
+        bob.say("bob").await;
+        assert_eq!(bob.line().await.as_deref(), Some("* in room: alice"));
+
+        let private = room(ChatConfig { reveal_roster: false, ..ChatConfig::default() });
+        let mut alice = TestClient::connect(&private, 4096).await;
+        alice.line().await;
+        alice.say("alice").await;
+        assert_eq!(alice.line().await.as_deref(), Some("* welcome"));
+        let mut bob = TestClient::connect(&private, 4096).await;
+        bob.line().await;
+        bob.say("bob").await;
+        assert_eq!(bob.line().await.as_deref(), Some("* welcome"));
+        assert_eq!(alice.line().await.as_deref(), Some("* bob entered"));
+    }
+
+    #[tokio::test]
+    async fn nickless_client_leaves_quietly() {
+        let (on_leave, left) = record_leaves();
+        let room = room(ChatConfig { on_leave: Some(on_leave), ..ChatConfig::default() });
+        let mut alice = TestClient::join(&room, "alice", 4096).await;
+        let mut lurker = TestClient::connect(&room, 4096).await;
+        lurker.line().await;
+        drop(lurker);
+        let mut invalid = TestClient::connect(&room, 4096).await;
+        invalid.line().await;
+        invalid.say("not valid!").await;
+        assert_eq!(invalid.line().await.as_deref(), Some("invalid nick"));
+
+        let _bob = TestClient::join(&room, "bob", 4096).await;
+        assert_eq!(alice.line().await.as_deref(), Some("* bob entered"));
+        assert!(left.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn nick_change() {
+        let room = room(ChatConfig::default());
+        let mut alice = TestClient::join(&room, "alice", 4096).await;
+        let mut bob = TestClient::join(&room, "bob", 4096).await;
+        assert_eq!(alice.line().await.as_deref(), Some("* bob entered"));
+
+        bob.say("/nick alice").await;
+        assert_eq!(bob.line().await.as_deref(), Some("* nick in use"));
+        bob.say("/nick robert").await;
+        assert_eq!(bob.line().await.as_deref(), Some("* bob is now known as robert"));
+        assert_eq!(alice.line().await.as_deref(), Some("* bob is now known as robert"));
+        bob.say("hi").await;
+        assert_eq!(alice.line().await.as_deref(), Some("[robert] hi"));
+    }
+
+    #[tokio::test]
+    async fn sequence_numbers() {
+        let room = room(ChatConfig { sequence_numbers: true, ..ChatConfig::default() });
+        let mut alice = TestClient::join(&room, "alice", 4096).await;
+        let mut bob = TestClient::join(&room, "bob", 4096).await;
+        // Alice's own entry was #1, though nobody was there to see it
+        assert_eq!(alice.line().await.as_deref(), Some("#2 * bob entered"));
+        bob.say("one").await;
+        assert_eq!(alice.line().await.as_deref(), Some("#3 [bob] one"));
+        alice.say("two").await;
+        assert_eq!(bob.line().await.as_deref(), Some("#4 [alice] two"));
+    }
+
+    #[tokio::test]
+    async fn full_room_admits_after_someone_leaves() {
+        let room = room(ChatConfig { max_clients: Some(2), ..ChatConfig::default() });
+        let mut alice = TestClient::join(&room, "alice", 4096).await;
+        let bob = TestClient::join(&room, "bob", 4096).await;
+        assert_eq!(alice.line().await.as_deref(), Some("* bob entered"));
+
+        let mut turned_away = TestClient::connect(&room, 4096).await;
+        assert_eq!(turned_away.line().await.as_deref(), Some("server full"));
+        assert_eq!(turned_away.line().await, None);
+
+        drop(bob);
+        assert_eq!(alice.line().await.as_deref(), Some("* bob left"));
+        let _carol = TestClient::join(&room, "carol", 4096).await;
+        assert_eq!(alice.line().await.as_deref(), Some("* carol entered"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_sender_is_disconnected() {
+        let min_throughput = MinThroughput { bytes: 10, window: Duration::from_secs(1) };
+        let room = room(ChatConfig { min_throughput: Some(min_throughput), ..ChatConfig::default() });
+        let mut idle = TestClient::join(&room, "idle", 4096).await;
+        let mut slow = TestClient::join(&room, "slow", 4096).await;
+        assert_eq!(idle.line().await.as_deref(), Some("* slow entered"));
+
+        slow.writer.write_all(b"a long message, one byte").await.unwrap();
+        // One byte a window from now on, until the room hangs up
+        while slow.writer.write_all(b".").await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(900)).await;
+        }
+        // Sitting on a finished line, however long, is fine; trickling out an unfinished one isn't
+        assert_eq!(idle.line().await.as_deref(), Some("* slow left"));
+        assert_eq!(slow.line().await, None);
+    }
+
+    #[tokio::test]
+    async fn prompt_and_motd() {
+        let room = room(ChatConfig { prompt: "who are you?".to_string(), motd: Some("be nice".to_string()), ..ChatConfig::default() });
+        let mut alice = TestClient::connect(&room, 4096).await;
+        assert_eq!(alice.line().await.as_deref(), Some("who are you?"));
+        alice.say("alice").await;
+        assert_eq!(alice.line().await.as_deref(), Some("* in room: "));
+        assert_eq!(alice.line().await.as_deref(), Some("be nice"));
+    }
+
+    #[tokio::test]
+    async fn reserved_nicks_are_refused() {
+        let room = room(ChatConfig { reserved_nicks: HashSet::from(["server".to_string()]), ..ChatConfig::default() });
+        let mut impostor = TestClient::connect(&room, 4096).await;
+        impostor.line().await;
+        impostor.say("SERVER").await;
+        assert_eq!(impostor.line().await.as_deref(), Some("nick reserved"));
+        assert_eq!(impostor.line().await, None);
+
+        let mut alice = TestClient::join(&room, "alice", 4096).await;
+        alice.say("/nick Server").await;
+        assert_eq!(alice.line().await.as_deref(), Some("* nick reserved"));
+    }
+
+    #[tokio::test]
+    async fn long_messages_are_truncated() {
+        let room = room(ChatConfig { max_message_len: Some(5), ..ChatConfig::default() });
+        let mut alice = TestClient::join(&room, "alice", 4096).await;
+        let mut bob = TestClient::join(&room, "bob", 4096).await;
+        assert_eq!(alice.line().await.as_deref(), Some("* bob entered"));
+        bob.say("hello there").await;
+        assert_eq!(alice.line().await.as_deref(), Some("[bob] hello..."));
+        bob.say("héllo").await;
+        assert_eq!(alice.line().await.as_deref(), Some("[bob] héllo"));
+        // Still connected
+        alice.say("hi").await;
+        assert_eq!(bob.line().await.as_deref(), Some("[alice] hi"));
+    }
+
+    #[tokio::test]
+    async fn away_status_in_who() {
+        let room = room(ChatConfig::default());
+        let mut alice = TestClient::join(&room, "alice", 4096).await;
+        let mut bob = TestClient::join(&room, "bob", 4096).await;
+        let mut carol = TestClient::join(&room, "carol", 4096).await;
+        assert_eq!(alice.line().await.as_deref(), Some("* bob entered"));
+        assert_eq!(alice.line().await.as_deref(), Some("* carol entered"));
+        assert_eq!(bob.line().await.as_deref(), Some("* carol entered"));
+        bob.say("/away lunch").await;
+        assert_eq!(bob.line().await.as_deref(), Some("* you are away"));
+        carol.say("/away").await;
+        assert_eq!(carol.line().await.as_deref(), Some("* you are away"));
+        alice.say("/who").await;
+        assert_eq!(alice.line().await.as_deref(), Some("* who: alice, bob (away: lunch), carol (away)"));
+
+        bob.say("/back").await;
+        assert_eq!(bob.line().await.as_deref(), Some("* you are back"));
+        alice.say("/who").await;
+        assert_eq!(alice.line().await.as_deref(), Some("* who: alice, bob, carol (away)"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flooding_escalates_to_a_mute_that_expires() {
+        let control = FloodControl {
+            messages: 2,
+            window: Duration::from_secs(1),
+            strikes: 2,
+            strike_window: Duration::from_secs(10),
+            mute: Duration::from_secs(5),
+        };
+        let mut flood = FloodState::new();
+        assert_eq!(flood.check(&control), Ok(()));
+        assert_eq!(flood.check(&control), Ok(()));
+        assert_eq!(flood.check(&control), Err(Flooding::TooFast));
+        assert_eq!(flood.check(&control), Err(Flooding::Muted));
+
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert_eq!(flood.check(&control), Err(Flooding::Muted));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(flood.check(&control), Ok(()));
+        assert_eq!(flood.check(&control), Ok(()));
+        // Strikes were cleared by the mute, so it takes two more to get muted again
+        assert_eq!(flood.check(&control), Err(Flooding::TooFast));
+
+        // Strikes further apart than the strike window don't add up
+        tokio::time::advance(Duration::from_secs(11)).await;
+        for _ in 0..2 {
+            assert_eq!(flood.check(&control), Ok(()));
+        }
+        assert_eq!(flood.check(&control), Err(Flooding::TooFast));
+    }
+}
@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+use std::future::{pending, Future};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU64;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::time::Interval;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone)]
+pub struct ListenOptions {
+    /// Set SO_REUSEADDR so a restarted server can bind while old connections sit in TIME_WAIT
+    pub reuse_addr: bool,
+    /// Maximum number of pending connections queued by the OS
+    pub backlog: u32,
+    /// Set IPV6_V6ONLY on IPv6 listeners, so an IPv4 listener can share their port
+    pub v6_only: bool,
+    /// Which client addresses may connect
+    pub sources: SourceFilter,
+    /// Log only every this many accepted connections, for servers with lots of short-lived ones
+    pub accept_log_every: NonZeroU64,
+    /// Most connections one source IP may have open at once, across all the listeners bound
+    /// together
+    pub max_connections_per_ip: Option<usize>,
+    /// Turn on TCP keepalive for accepted connections, probing after they've been idle this
+    /// long and then this often, so a peer that vanished without closing is eventually noticed
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for ListenOptions {
+    fn default() -> Self {
+        // Same as tokio's TcpListener::bind
+        ListenOptions {
+            reuse_addr: true,
+            backlog: 1024,
+            v6_only: false,
+            sources: SourceFilter::default(),
+            accept_log_every: NonZeroU64::MIN,
+            max_connections_per_ip: None,
+            keepalive: None,
+        }
+    }
+}
+
+/// An IPv4 or IPv6 network, e.g. `10.0.0.0/8`. A bare address is a network of just that address.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // So IPv4 clients of a dual-stack IPv6 listener still match IPv4 networks
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix) = match s.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = network.parse().map_err(|e| format!("bad address in {s:?}: {e}"))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|e| format!("bad prefix length in {s:?}: {e}"))?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(format!("prefix length in {s:?} is longer than {max_prefix}"));
+        }
+        Ok(Cidr { network, prefix })
+    }
+}
+
+/// Allow and deny lists of client networks. With an empty allow list any source not denied may
+/// connect; denials always win.
+#[derive(Debug, Clone, Default)]
+pub struct SourceFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl SourceFilter {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip)))
+            && !self.deny.iter().any(|c| c.contains(ip))
+    }
+}
+
+/// Set once the server starts draining, after which listeners turn every new client away
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Stop taking new connections on every `Listener`, while existing connections carry on, e.g. so
+/// a replacement server can take over during a deploy. There's no way back.
+pub fn drain() {
+    if !DRAINING.swap(true, Ordering::Relaxed) {
+        info!("draining, no longer accepting connections");
+    }
+}
+
+pub fn draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+/// Counts each source IP's open connections, so listeners sharing one share the limit
+#[derive(Debug, Clone)]
+struct PerIpLimit {
+    max: usize,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl PerIpLimit {
+    fn new(max: usize) -> PerIpLimit {
+        PerIpLimit { max, open: Arc::default() }
+    }
+
+    /// Count a new connection from `ip`, unless it already has as many as it may
+    fn acquire(&self, ip: IpAddr) -> Option<ConnectionPermit> {
+        let ip = ip.to_canonical();
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_default();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionPermit { limit: Some((self.clone(), ip)) })
+    }
+}
+
+/// Keeps an accepted connection counted against its source IP's limit until it's dropped
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    limit: Option<(PerIpLimit, IpAddr)>,
+}
+
+impl ConnectionPermit {
+    /// Run a connection's handler, keeping the permit until it finishes
+    pub async fn hold<F: Future>(self, handler: F) -> F::Output {
+        let output = handler.await;
+        drop(self);
+        output
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some((ref limit, ip)) = self.limit {
+            let mut open = limit.open.lock().unwrap();
+            if let Some(count) = open.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    open.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// A TCP listener that turns away clients its `SourceFilter` doesn't allow, sources with too many
+/// connections open already, and everyone once the server is draining
+#[derive(Debug)]
+pub struct Listener {
+    listener: TcpListener,
+    sources: SourceFilter,
+    accept_log_every: NonZeroU64,
+    accepts_logged: AtomicU64,
+    per_ip: Option<PerIpLimit>,
+    keepalive: Option<Duration>,
+}
+
+impl Listener {
+    pub fn new(listener: TcpListener, sources: SourceFilter) -> Listener {
+        Listener { listener, sources, accept_log_every: NonZeroU64::MIN, accepts_logged: AtomicU64::new(0), per_ip: None, keepalive: None }
+    }
+
+    /// Probe idle connections with TCP keepalive after `idle`, and every `idle` after that
+    pub fn with_keepalive(mut self, idle: Duration) -> Listener {
+        self.keepalive = Some(idle);
+        self
+    }
+
+    /// Let each source IP have at most `max` connections open at once
+    pub fn with_max_connections_per_ip(mut self, max: usize) -> Listener {
+        self.per_ip = Some(PerIpLimit::new(max));
+        self
+    }
+
+    /// Log only every `every`th accepted connection
+    pub fn with_accept_log_every(mut self, every: NonZeroU64) -> Listener {
+        self.accept_log_every = every;
+        self
+    }
+
+    /// Whether a connection just accepted should be logged: call this once per connection. It's
+    /// true for the first, then for every `accept_log_every`th.
+    pub fn log_accept(&self) -> bool {
+        self.accepts_logged.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.accept_log_every.get())
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Wait for a connection from an allowed source, closing any others as they arrive. The
+    /// permit should be kept for as long as the connection is open.
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr, ConnectionPermit)> {
+        loop {
+            let (stream, addr) = self.listener.accept().await?;
+            if let Some(idle) = self.keepalive {
+                let keepalive = TcpKeepalive::new().with_time(idle).with_interval(idle);
+                if let Err(e) = SockRef::from(&stream).set_tcp_keepalive(&keepalive) {
+                    warn!(client=%addr, error=%e, "couldn't enable keepalive");
+                }
+            }
+            if draining() {
+                info!(client=%addr, "draining, closing");
+            } else if !self.sources.allows(addr.ip()) {
+                warn!(client=%addr, "source not allowed, closing");
+            } else {
+                match self.per_ip {
+                    None => return Ok((stream, addr, ConnectionPermit { limit: None })),
+                    Some(ref per_ip) => match per_ip.acquire(addr.ip()) {
+                        Some(permit) => return Ok((stream, addr, permit)),
+                        None => warn!(client=%addr, max=per_ip.max, "too many connections from source, closing"),
+                    },
+                }
+            }
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::new(listener, SourceFilter::default())
+    }
+}
+
+/// Bind a TCP listener, honouring the socket options shared by all the TCP problems
+pub fn bind(address: SocketAddr, options: &ListenOptions) -> io::Result<Listener> {
+    bind_sharing(address, options, options.max_connections_per_ip.map(PerIpLimit::new))
+}
+
+fn bind_sharing(address: SocketAddr, options: &ListenOptions, per_ip: Option<PerIpLimit>) -> io::Result<Listener> {
+    let socket = if address.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket.set_reuseaddr(options.reuse_addr)?;
+    if address.is_ipv6() {
+        SockRef::from(&socket).set_only_v6(options.v6_only)?;
+    }
+    socket.bind(address)?;
+    let listener = socket.listen(options.backlog)?;
+    info!(address=%address, options=?options, "listening");
+    let mut listener = Listener::new(listener, options.sources.clone()).with_accept_log_every(options.accept_log_every);
+    listener.per_ip = per_ip;
+    listener.keepalive = options.keepalive;
+    Ok(listener)
+}
+
+/// Bind a listener on every address, e.g. one IPv4 and one IPv6 address for dual-stack
+pub fn bind_all(addresses: &[SocketAddr], options: &ListenOptions) -> io::Result<Vec<Listener>> {
+    let per_ip = options.max_connections_per_ip.map(PerIpLimit::new);
+    addresses.iter().map(|&address| bind_sharing(address, options, per_ip.clone())).collect()
+}
+
+/// Write the whole of `data`, logging any failure. An error means the peer is gone or the
+/// socket is broken, so callers should treat it as the connection closing.
+pub async fn send<W: AsyncWrite + Unpin + ?Sized>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    writer.write_all(data).await
+        .inspect_err(|e| warn!(error=%e, bytes=data.len(), "write failed"))
+}
+
+/// Flush buffered writes, logging any failure. As with `send`, an error means the connection
+/// is unusable.
+pub async fn flush<W: AsyncWrite + Unpin + ?Sized>(writer: &mut W) -> io::Result<()> {
+    writer.flush().await
+        .inspect_err(|e| warn!(error=%e, "flush failed"))
+}
+
+/// Wait for the interval's next tick, or forever if there isn't one. Idle timers never fire, so a
+/// test running under `tokio::time::pause` can skip ahead without spurious ticks.
+pub async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(i) => {
+            i.tick().await;
+        }
+        None => pending().await,
+    }
+}
+
+/// Running totals for a single connection, reported when it closes
+#[derive(Debug)]
+pub struct ConnectionStats {
+    started: Instant,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        ConnectionStats {
+            started: Instant::now(),
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+}
+
+impl ConnectionStats {
+    pub fn new() -> ConnectionStats {
+        ConnectionStats::default()
+    }
+
+    pub fn read(&mut self, bytes: usize) {
+        self.bytes_read += bytes as u64;
+    }
+
+    pub fn wrote(&mut self, bytes: usize) {
+        self.bytes_written += bytes as u64;
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use tokio::io::AsyncReadExt;
+    use tokio::time::timeout;
+
+    #[test]
+    fn per_ip_limit_counts_open_connections() {
+        let limit = PerIpLimit::new(2);
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let first = limit.acquire(ip).unwrap();
+        let _second = limit.acquire(ip).unwrap();
+        assert!(limit.acquire(ip).is_none());
+        // The same address seen over IPv6 shares the limit
+        assert!(limit.acquire(IpAddr::V6(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped())).is_none());
+        assert!(limit.acquire(IpAddr::V6(Ipv6Addr::LOCALHOST)).is_some());
+
+        drop(first);
+        assert!(limit.acquire(ip).is_some());
+    }
+
+    #[tokio::test]
+    async fn listener_turns_away_connections_over_the_limit() {
+        let listener = Listener::from(TcpListener::bind("127.0.0.1:0").await.unwrap()).with_max_connections_per_ip(2);
+        let addr = listener.local_addr().unwrap();
+        let mut clients = Vec::new();
+        for _ in 0..5 {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+        }
+        let (_, _, first) = listener.accept().await.unwrap();
+        let (_, _, _second) = listener.accept().await.unwrap();
+
+        // The rest are closed as soon as they're accepted
+        assert!(timeout(Duration::from_millis(200), listener.accept()).await.is_err());
+        for client in &mut clients[2..] {
+            assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+        }
+
+        drop(first);
+        let _late = TcpStream::connect(addr).await.unwrap();
+        timeout(Duration::from_secs(5), listener.accept()).await.expect("still over the limit").unwrap();
+    }
+
+    #[tokio::test]
+    async fn rebind_after_closing_connections() {
+        let options = ListenOptions { reuse_addr: true, ..ListenOptions::default() };
+        let listener = bind("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (server, _, _) = listener.accept().await.unwrap();
+        // Closing from the server's end leaves its side of the connection in TIME_WAIT
+        drop(server);
+        assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+        drop(client);
+        drop(listener);
+
+        let listener = bind(addr, &options).expect("rebind failed");
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn send_to_a_closed_peer_fails() {
+        let (mut ours, theirs) = tokio::io::duplex(64);
+        send(&mut ours, b"hello").await.unwrap();
+        drop(theirs);
+        let e = send(&mut ours, b"goodbye").await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn cidr_matching() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.255.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        // IPv4 clients of a dual-stack listener show up as mapped IPv6 addresses
+        assert!(net.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!net.contains("2001:db8::1".parse().unwrap()));
+
+        let host: Cidr = "2001:db8::1".parse().unwrap();
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains("192.0.2.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[tokio::test]
+    async fn listener_turns_away_denied_sources() {
+        let sources = SourceFilter { allow: vec!["127.0.0.0/8".parse().unwrap()], deny: vec!["127.0.0.2".parse().unwrap()] };
+        let listener = Listener::new(TcpListener::bind("127.0.0.1:0").await.unwrap(), sources);
+        let addr = listener.local_addr().unwrap();
+        let connect_from = |source: &str| {
+            let socket = TcpSocket::new_v4().unwrap();
+            socket.bind(format!("{source}:0").parse().unwrap()).unwrap();
+            socket.connect(addr)
+        };
+
+        let mut denied = connect_from("127.0.0.2").await.unwrap();
+        let _allowed = connect_from("127.0.0.1").await.unwrap();
+        let (_, client, _) = listener.accept().await.unwrap();
+        assert_eq!(client.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(denied.read(&mut [0u8; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn accept_log_sampling() {
+        let listener = Listener::from(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        assert!((0..5).all(|_| listener.log_accept()));
+
+        let listener = Listener::from(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .with_accept_log_every(NonZeroU64::new(10).unwrap());
+        let logged: Vec<bool> = (0..100).map(|_| listener.log_accept()).collect();
+        assert_eq!(logged.iter().filter(|&&l| l).count(), 10);
+        assert!(logged[0] && logged[10] && !logged[9]);
+    }
+
+    #[tokio::test]
+    async fn keepalive_is_set_on_accepted_connections() {
+        for keepalive in [None, Some(Duration::from_secs(30))] {
+            let options = ListenOptions { keepalive, ..ListenOptions::default() };
+            let listener = bind("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+            let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (stream, _, _) = listener.accept().await.unwrap();
+            let socket = SockRef::from(&stream);
+            assert_eq!(socket.keepalive().unwrap(), keepalive.is_some());
+            #[cfg(target_os = "linux")]
+            if let Some(idle) = keepalive {
+                assert_eq!(socket.keepalive_time().unwrap(), idle);
+                assert_eq!(socket.keepalive_interval().unwrap(), idle);
+            }
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! Draining can't be undone and affects every listener in the process, so it gets a test binary
+//! of its own
+
+use std::time::Duration;
+use common::{drain, draining, Listener};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn draining_turns_away_new_connections() {
+    let listener = Listener::from(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    let addr = listener.local_addr().unwrap();
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    let (mut existing, _, _) = listener.accept().await.unwrap();
+
+    assert!(!draining());
+    drain();
+    assert!(draining());
+    let mut late = TcpStream::connect(addr).await.unwrap();
+    assert!(timeout(Duration::from_millis(200), listener.accept()).await.is_err());
+    assert_eq!(late.read(&mut [0u8; 1]).await.unwrap(), 0);
+
+    // Connections from before carry on
+    existing.write_all(b"still here").await.unwrap();
+    let mut buf = [0u8; 10];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"still here");
+}
@@ -1,11 +1,45 @@
 use std::collections::BTreeMap;
 use std::io;
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use bincode::Decode;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
+use common::{send, ConnectionStats, Listener};
+use lru::LruCache;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
 use tracing::{debug, error, info, info_span, warn};
 
+#[derive(Debug, Clone)]
+pub struct MeansConfig {
+    /// Close connections that send nothing for this long
+    pub read_timeout: Duration,
+    /// Reply to a `Q` whose end is before its start with this, rather than treating it as an
+    /// empty range
+    pub inverted_range_reply: i32,
+    /// Let clients resume their price history from an earlier connection by opening with an
+    /// `S` frame carrying a session id. Keeps up to this many sessions, forgetting the least
+    /// recently resumed first.
+    pub max_sessions: Option<NonZeroUsize>,
+    /// Expect each request to be preceded by a big-endian u16 length, which must be 9. Replies
+    /// are sent without a length.
+    pub length_prefixed: bool,
+}
+
+impl Default for MeansConfig {
+    fn default() -> Self {
+        MeansConfig {
+            read_timeout: Duration::from_secs(300),
+            inverted_range_reply: 0,
+            max_sessions: None,
+            length_prefixed: false,
+        }
+    }
+}
+
 #[derive(Decode, Debug)]
 struct Request {
     command: u8,
@@ -13,13 +47,35 @@ struct Request {
     b: i32,
 }
 
+type Session = Arc<Mutex<ClientData>>;
+
+/// Price histories kept after their connection closes, by session id
+type Sessions = Mutex<LruCache<i32, Session>>;
+
 #[derive(Debug, Default)]
 struct ClientData {
-    price_history: BTreeMap<i32, i32>
+    price_history: BTreeMap<i32, i32>,
+    counts: RequestCounts,
+}
+
+/// What a session has asked for, to spot clients misusing the protocol
+#[derive(Debug, Default)]
+struct RequestCounts {
+    inserts: u64,
+    deletes: u64,
+    /// Every request with a reply, including ones that failed
+    queries: u64,
+    /// Unknown commands and queries with bad arguments
+    errors: u64,
 }
 
 impl ClientData {
-    fn apply_request(&mut self, request: &Request) -> Option<i32> {
+    fn apply_request(&mut self, request: &Request, config: &MeansConfig) -> Option<i32> {
+        match request.command {
+            b'I' => self.counts.inserts += 1,
+            b'D' => self.counts.deletes += 1,
+            _ => self.counts.queries += 1,
+        }
         match request.command {
             b'I' => {
                 let timestamp = request.a;
@@ -28,39 +84,181 @@ impl ClientData {
                 self.price_history.insert(timestamp, price);
                 None
             }
+            b'D' => {
+                // Remove every price from timestamp `a` to `b` inclusive; like `I`, no reply
+                let start = request.a;
+                let end = request.b;
+                let before = self.price_history.len();
+                self.price_history.retain(|&timestamp, _| !(start..=end).contains(&timestamp));
+                debug!(start=start, end=end, deleted=before - self.price_history.len(), "delete");
+                None
+            }
             b'Q' => {
                 let start = request.a;
                 let end = request.b;
                 debug!(start=start, end=end, "query");
+                if end < start {
+                    warn!(start=start, end=end, reply=config.inverted_range_reply, "query range is inverted");
+                    self.counts.errors += 1;
+                    return Some(config.inverted_range_reply);
+                }
                 let mut total: i64 = 0;
                 let mut count: i64 = 0;
-                if end >= start {
-                    for (_timestamp, price) in self.price_history.range(start..=end) {
-                        total += *price as i64;
-                        count += 1;
-                    }
+                for (_timestamp, price) in self.price_history.range(start..=end) {
+                    total += *price as i64;
+                    count += 1;
                 }
-                let average = total.checked_div(count).unwrap_or(0) as i32;
+                if count == 0 {
+                    debug!(start=start, end=end, "no prices in query range");
+                    return Some(0);
+                }
+                let average = (total / count) as i32;
                 debug!(total=total, count=count, average=average, "query result");
                 Some(average)
             }
+            b'V' => {
+                // Population standard deviation of the prices from `a` to `b` inclusive, rounded
+                let start = request.a;
+                let end = request.b;
+                debug!(start=start, end=end, "stddev");
+                if end < start {
+                    warn!(start=start, end=end, reply=config.inverted_range_reply, "stddev range is inverted");
+                    self.counts.errors += 1;
+                    return Some(config.inverted_range_reply);
+                }
+                let prices = || self.price_history.range(start..=end).map(|(_timestamp, &price)| price as f64);
+                let count = prices().count();
+                if count < 2 {
+                    return Some(0);
+                }
+                let mean = prices().sum::<f64>() / count as f64;
+                let variance = prices().map(|price| (price - mean).powi(2)).sum::<f64>() / count as f64;
+                let stddev = variance.sqrt();
+                debug!(count=count, mean=mean, stddev=stddev, "stddev result");
+                Some(stddev.round() as i32)
+            }
+            b'M' => {
+                // Median of the prices from `a` to `b` inclusive, averaging the middle two (and
+                // rounding down) when there's an even number of them
+                let start = request.a;
+                let end = request.b;
+                debug!(start=start, end=end, "median");
+                if end < start {
+                    warn!(start=start, end=end, reply=config.inverted_range_reply, "median range is inverted");
+                    self.counts.errors += 1;
+                    return Some(config.inverted_range_reply);
+                }
+                let mut prices: Vec<i32> = self.price_history.range(start..=end).map(|(_timestamp, &price)| price).collect();
+                if prices.is_empty() {
+                    return Some(0);
+                }
+                prices.sort_unstable();
+                let middle = prices.len() / 2;
+                let median = if prices.len() % 2 == 1 {
+                    prices[middle]
+                } else {
+                    ((prices[middle - 1] as i64 + prices[middle] as i64).div_euclid(2)) as i32
+                };
+                debug!(count=prices.len(), median=median, "median result");
+                Some(median)
+            }
+            b'W' => {
+                // Time-weighted average of the prices from `a` to `b` inclusive. Each price holds
+                // from its timestamp until the next one in the range, and the last holds until `b`
+                // inclusive, so it always counts for at least one second. Time in the range before
+                // its first price isn't counted, since nothing was known then.
+                let start = request.a;
+                let end = request.b;
+                debug!(start=start, end=end, "twap");
+                if end < start {
+                    warn!(start=start, end=end, reply=config.inverted_range_reply, "twap range is inverted");
+                    self.counts.errors += 1;
+                    return Some(config.inverted_range_reply);
+                }
+                let prices: Vec<(i32, i32)> = self.price_history.range(start..=end).map(|(&timestamp, &price)| (timestamp, price)).collect();
+                let mut total: i128 = 0;
+                let mut duration: i128 = 0;
+                for (i, &(timestamp, price)) in prices.iter().enumerate() {
+                    let until = prices.get(i + 1).map_or(end as i128 + 1, |&(next, _)| next as i128);
+                    let held = until - timestamp as i128;
+                    total += price as i128 * held;
+                    duration += held;
+                }
+                if duration == 0 {
+                    debug!(start=start, end=end, "no prices in twap range");
+                    return Some(0);
+                }
+                let twap = (total / duration) as i32;
+                debug!(total=%total, duration=%duration, twap=twap, "twap result");
+                Some(twap)
+            }
+            b'N' => {
+                let count = i32::try_from(self.price_history.len()).unwrap_or(i32::MAX);
+                debug!(count=count, "count");
+                Some(count)
+            }
+            b'E' => {
+                // Exponential moving average of every price up to and including timestamp `a`, in
+                // timestamp order, starting from the first price. `b` is the smoothing factor alpha
+                // in millionths, so 500000 weights each new price by 0.5; it must be 1 to 1000000.
+                let end = request.a;
+                let alpha_millionths = request.b;
+                debug!(end=end, alpha_millionths=alpha_millionths, "ema");
+                if !(1..=1_000_000).contains(&alpha_millionths) {
+                    warn!(alpha_millionths=alpha_millionths, "ema smoothing factor out of range");
+                    self.counts.errors += 1;
+                    return Some(-1);
+                }
+                let alpha = alpha_millionths as f64 / 1_000_000.0;
+                let ema = self.price_history.range(..=end)
+                    .map(|(_timestamp, &price)| price as f64)
+                    .reduce(|ema, price| alpha * price + (1.0 - alpha) * ema);
+                debug!(ema=ema, "ema result");
+                Some(ema.map_or(0, |ema| ema.round() as i32))
+            }
+            b'T' => {
+                // Unlike a one-timestamp `Q`, this is the price stored at exactly timestamp `a`,
+                // or 0 if there isn't one; `b` is ignored
+                let timestamp = request.a;
+                let price = self.price_history.get(&timestamp).copied();
+                debug!(timestamp=timestamp, price=?price, "price at");
+                Some(price.unwrap_or(0))
+            }
+            b'P' => {
+                let latest = self.price_history.last_key_value();
+                debug!(latest=?latest, "latest price");
+                Some(latest.map_or(0, |(_timestamp, &price)| price))
+            }
             _ => {
                 error!(request=?request, "unexpected command");
+                self.counts.errors += 1;
                 Some(-1)
             }
         }
     }
 }
 
-pub async fn serve(address: SocketAddr) -> io::Result<()> {
-    info!("starting");
-    let listener = TcpListener::bind(address).await?;
+/// Serve on every listener at once, e.g. one per address family
+pub async fn serve(listeners: Vec<Listener>, config: MeansConfig) -> io::Result<()> {
+    info!(listeners=listeners.len(), "starting");
 
+    let sessions = config.max_sessions.map(|max| Arc::new(Mutex::new(LruCache::new(max))));
+    let mut accepting = JoinSet::new();
+    for listener in listeners {
+        accepting.spawn(accept_loop(listener, config.clone(), sessions.clone()));
+    }
+    while accepting.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn accept_loop(listener: Listener, config: MeansConfig, sessions: Option<Arc<Sessions>>) {
     loop {
         match listener.accept().await {
-            Ok((stream, addr)) => {
-                info!(client=%addr, "connection received");
-                tokio::spawn(handle(stream, addr));
+            Ok((stream, addr, permit)) => {
+                if listener.log_accept() {
+                    info!(client=%addr, ipv6=addr.is_ipv6(), "connection received");
+                }
+                tokio::spawn(permit.hold(handle(stream, addr, config.clone(), sessions.clone())));
             }
             Err(e) => {
                 error!(error=?e, "accept failed");
@@ -69,34 +267,89 @@ pub async fn serve(address: SocketAddr) -> io::Result<()> {
     }
 }
 
-async fn handle(stream: TcpStream, addr: SocketAddr) {
+/// Every message, in either direction, is a one-byte command followed by two big-endian i32s
+const FRAME_LEN: usize = 9;
+
+/// Replies are a bare big-endian i32. This is written out by hand rather than through bincode so
+/// a change to the decoding config can't change the bytes clients see.
+fn encode_reply(reply: i32) -> [u8; 4] {
+    reply.to_be_bytes()
+}
+
+async fn handle(stream: TcpStream, addr: SocketAddr, config: MeansConfig, sessions: Option<Arc<Sessions>>) {
     let bincode_config = bincode::config::standard()
         .with_big_endian()
         .with_fixed_int_encoding();
 
-    let (reader, mut writer) = stream.into_split();
-    let mut buf_reader = BufReader::new(reader);
-    let mut command_buf = vec![0u8; 9];
-    let mut data = ClientData::default();
+    let (mut reader, mut writer) = stream.into_split();
+    let mut buf = Vec::with_capacity(1024);
+    let mut data = Session::default();
+    let mut first_frame = true;
     let mut connected = true;
     let span = info_span!("connection", client=%addr);
+    let mut stats = ConnectionStats::new();
 
     while connected {
-        match buf_reader.read_exact(&mut command_buf).await {
+        let read = match timeout(config.read_timeout, reader.read_buf(&mut buf)).await {
+            Ok(r) => r,
+            Err(_) => {
+                info!(client=%addr, timeout=?config.read_timeout, "idle timeout, closing");
+                break;
+            }
+        };
+        match read {
+            Ok(0) => {
+                debug!(client=%addr, unparsed=buf.len(), "client closed connection");
+                connected = false;
+            }
             Ok(n) => {
-                debug!(client=%addr, bytes=n, data=?command_buf, "read ok");
-                if n == 0 { connected = false; }
-                let (request, _bytes_read): (Request, _) = bincode::decode_from_slice(&command_buf, bincode_config).unwrap();
-                if let Some(reply) = span.in_scope(|| data.apply_request(&request)) {
-                    let reply_buf = bincode::encode_to_vec(reply, bincode_config).unwrap();
+                stats.read(n);
+                debug!(client=%addr, bytes=n, data=?buf, "read ok");
+
+                // Apply every complete frame we have, in order, and send their replies together
+                let mut reply_buf = Vec::new();
+                let prefix_len = if config.length_prefixed { 2 } else { 0 };
+                let mut consumed = 0;
+                loop {
+                    let rest = &buf[consumed..];
+                    if config.length_prefixed && rest.len() >= 2 {
+                        let length = u16::from_be_bytes([rest[0], rest[1]]);
+                        if length as usize != FRAME_LEN {
+                            error!(client=%addr, length=length, "bad frame length, closing");
+                            connected = false;
+                            break;
+                        }
+                    }
+                    if rest.len() < prefix_len + FRAME_LEN {
+                        break;
+                    }
+                    let frame = &rest[prefix_len..prefix_len + FRAME_LEN];
+                    consumed += prefix_len + FRAME_LEN;
+                    let (request, _bytes_read): (Request, _) = bincode::decode_from_slice(frame, bincode_config).unwrap();
+                    if let (true, b'S', Some(sessions)) = (first_frame, request.command, &sessions) {
+                        let id = request.a;
+                        info!(client=%addr, session=id, "resuming session");
+                        data = sessions.lock().unwrap().get_or_insert(id, Session::default).clone();
+                        first_frame = false;
+                        continue;
+                    }
+                    first_frame = false;
+                    if let Some(reply) = span.in_scope(|| data.lock().unwrap().apply_request(&request, &config)) {
+                        reply_buf.extend_from_slice(&encode_reply(reply));
+                    }
+                }
+                buf.drain(..consumed);
+
+                if !reply_buf.is_empty() {
                     debug!(client=%addr, data=?reply_buf, "sending reply");
 
-                    match writer.write_all(&reply_buf).await {
+                    match send(&mut writer, &reply_buf).await {
                         Ok(()) => {
+                            stats.wrote(reply_buf.len());
                             debug!(client=%addr, bytes=reply_buf.len(), "write ok");
                         }
-                        Err(e) => {
-                            warn!(client=%addr, error=%e, "write failed");
+                        Err(_) => {
+                            warn!(client=%addr, "closing after failed write");
                             break;
                         }
                     }
@@ -109,5 +362,308 @@ async fn handle(stream: TcpStream, addr: SocketAddr) {
             }
         }
     }
-    info!(client=%addr, "disconnect");
+    let counts = &data.lock().unwrap().counts;
+    info!(client=%addr, duration=?stats.duration(), bytes_read=stats.bytes_read, bytes_written=stats.bytes_written,
+        inserts=counts.inserts, deletes=counts.deletes, queries=counts.queries, errors=counts.errors, "disconnect");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::time::Instant;
+
+    /// Log lines written on this thread, which is every task's in a single-threaded test
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Logs {
+        fn capture(&self) -> tracing::subscriber::DefaultGuard {
+            let logs = self.clone();
+            let subscriber = tracing_subscriber::fmt().with_ansi(false).with_max_level(tracing::Level::DEBUG).with_writer(move || logs.clone()).finish();
+            tracing::subscriber::set_default(subscriber)
+        }
+
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).lines().map(str::to_string).collect()
+        }
+
+        /// The first line containing `needle`, giving tasks a moment to log it
+        async fn wait_for(&self, needle: &str) -> String {
+            for _ in 0..500 {
+                if let Some(line) = self.lines().into_iter().find(|l| l.contains(needle)) {
+                    return line;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("never logged {needle:?}: {:#?}", self.lines());
+        }
+    }
+
+    /// The value logged for `name` in a log line
+    fn field(line: &str, name: &str) -> String {
+        line.split(&format!(" {name}=")).nth(1).unwrap().split(' ').next().unwrap().to_string()
+    }
+
+    async fn start(config: MeansConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(vec![Listener::from(listener)], config));
+        addr
+    }
+
+    #[test]
+    fn replies_are_big_endian() {
+        assert_eq!(encode_reply(0x01020304), [1, 2, 3, 4]);
+        assert_eq!(encode_reply(-1), [0xff; 4]);
+    }
+
+    #[test]
+    fn query_reply_bytes() {
+        let config = MeansConfig::default();
+        let mut data = ClientData::default();
+        for (timestamp, price) in [(12345, 101), (12346, 102), (12347, 100), (40960, 5)] {
+            assert_eq!(data.apply_request(&Request { command: b'I', a: timestamp, b: price }, &config), None);
+        }
+        let mean = data.apply_request(&Request { command: b'Q', a: 12288, b: 16384 }, &config).unwrap();
+        assert_eq!(encode_reply(mean), [0x00, 0x00, 0x00, 0x65]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_connection_is_closed() {
+        let addr = start(MeansConfig { read_timeout: Duration::from_secs(30), ..MeansConfig::default() }).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let started = Instant::now();
+        let mut rest = Vec::new();
+        timeout(Duration::from_secs(60), client.read_to_end(&mut rest)).await.expect("still open").unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(30));
+        assert!(rest.is_empty());
+    }
+
+    /// Client data holding `prices`, as (timestamp, price) pairs
+    fn history(prices: &[(i32, i32)]) -> ClientData {
+        let mut data = ClientData::default();
+        for &(timestamp, price) in prices {
+            ask(&mut data, b'I', timestamp, price);
+        }
+        data
+    }
+
+    fn ask(data: &mut ClientData, command: u8, a: i32, b: i32) -> Option<i32> {
+        data.apply_request(&Request { command, a, b }, &MeansConfig::default())
+    }
+
+    #[test]
+    fn count_points() {
+        let mut data = history(&[(1, 10), (2, 20), (3, 30)]);
+        assert_eq!(ask(&mut data, b'N', 0, 0), Some(3));
+        // Replacing a price doesn't add a point
+        ask(&mut data, b'I', 2, 25);
+        assert_eq!(ask(&mut data, b'N', 0, 0), Some(3));
+    }
+
+    fn frame(command: u8, a: i32, b: i32) -> Vec<u8> {
+        [&[command][..], &a.to_be_bytes(), &b.to_be_bytes()].concat()
+    }
+
+    #[tokio::test]
+    async fn disconnect_is_logged_with_stats() {
+        let logs = Logs::default();
+        let _guard = logs.capture();
+        let addr = start(MeansConfig::default()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[frame(b'I', 100, 42), frame(b'Q', 0, 1000)].concat()).await.unwrap();
+        client.shutdown().await.unwrap();
+        // Wait for the server to close its side too
+        let mut reply = Vec::new();
+        timeout(Duration::from_secs(5), client.read_to_end(&mut reply)).await.expect("still open").unwrap();
+        assert_eq!(reply, 42i32.to_be_bytes());
+
+        let line = logs.wait_for(" disconnect ").await;
+        assert_eq!(field(&line, "bytes_read"), "18");
+        assert_eq!(field(&line, "bytes_written"), "4");
+        assert_ne!(field(&line, "duration"), "0ns");
+    }
+
+    #[tokio::test]
+    async fn frames_sharing_a_write_are_all_applied() {
+        let addr = start(MeansConfig::default()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let both = [frame(b'I', 100, 42), frame(b'Q', 0, 1000)].concat();
+        client.write_all(&both).await.unwrap();
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(i32::from_be_bytes(reply), 42);
+
+        // A frame split across writes waits for the rest
+        let query = frame(b'Q', 0, 1000);
+        client.write_all(&query[..5]).await.unwrap();
+        assert!(timeout(Duration::from_millis(200), client.read_exact(&mut reply)).await.is_err());
+        client.write_all(&query[5..]).await.unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(i32::from_be_bytes(reply), 42);
+    }
+
+    #[test]
+    fn empty_and_inverted_queries() {
+        let logs = Logs::default();
+        let _guard = logs.capture();
+        let mut data = history(&[(100, 10)]);
+        assert_eq!(ask(&mut data, b'Q', 0, 50), Some(0));
+        assert_eq!(data.counts.errors, 0);
+        assert_eq!(ask(&mut data, b'Q', 200, 0), Some(0));
+        assert_eq!(data.counts.errors, 1);
+        // Both answer 0, but say why differently
+        let lines = logs.lines();
+        let logged = |level: &str, message: &str, range: &str| lines.iter().any(|l| l.contains(level) && l.contains(message) && l.contains(range));
+        assert!(logged(" DEBUG ", "no prices in query range", "start=0 end=50"), "{lines:#?}");
+        assert!(logged(" WARN ", "query range is inverted", "start=200 end=0"), "{lines:#?}");
+        assert!(!logged("", "query range is inverted", "start=0 end=50"), "{lines:#?}");
+        assert!(!logged("", "no prices in query range", "start=200 end=0"), "{lines:#?}");
+
+        let config = MeansConfig { inverted_range_reply: -1, ..MeansConfig::default() };
+        assert_eq!(data.apply_request(&Request { command: b'Q', a: 200, b: 0 }, &config), Some(-1));
+    }
+
+    #[test]
+    fn latest_price() {
+        let mut data = history(&[]);
+        assert_eq!(ask(&mut data, b'P', 0, 0), Some(0));
+        let mut data = history(&[(300, 3), (100, 1), (500, 5), (200, 2)]);
+        assert_eq!(ask(&mut data, b'P', 0, 0), Some(5));
+    }
+
+    /// Send `frames` on a new connection and read back `replies` replies
+    async fn exchange(addr: SocketAddr, frames: &[Vec<u8>], replies: usize) -> Vec<i32> {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&frames.concat()).await.unwrap();
+        let mut buf = vec![0u8; replies * 4];
+        client.read_exact(&mut buf).await.unwrap();
+        buf.chunks(4).map(|r| i32::from_be_bytes(r.try_into().unwrap())).collect()
+    }
+
+    #[tokio::test]
+    async fn session_resumes_on_reconnect() {
+        let addr = start(MeansConfig { max_sessions: NonZeroUsize::new(10), ..MeansConfig::default() }).await;
+        let first = exchange(addr, &[frame(b'S', 7, 0), frame(b'I', 100, 42), frame(b'N', 0, 0)], 1).await;
+        assert_eq!(first, [1]);
+        let resumed = exchange(addr, &[frame(b'S', 7, 0), frame(b'Q', 0, 1000)], 1).await;
+        assert_eq!(resumed, [42]);
+        let other = exchange(addr, &[frame(b'S', 8, 0), frame(b'Q', 0, 1000)], 1).await;
+        assert_eq!(other, [0]);
+    }
+
+    #[tokio::test]
+    async fn length_prefixed_frames() {
+        let addr = start(MeansConfig { length_prefixed: true, ..MeansConfig::default() }).await;
+        let prefixed = |frame: Vec<u8>| [vec![0, 9], frame].concat();
+        let replies = exchange(addr, &[prefixed(frame(b'I', 100, 42)), prefixed(frame(b'Q', 0, 1000))], 1).await;
+        assert_eq!(replies, [42]);
+
+        // Unprefixed frames are read as a bad length, and the connection closed
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&frame(b'Q', 0, 1000)).await.unwrap();
+        let mut rest = Vec::new();
+        timeout(Duration::from_secs(5), client.read_to_end(&mut rest)).await.expect("still open").unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn exponential_moving_average() {
+        let mut data = history(&[(1, 10), (2, 20), (3, 40)]);
+        // 10, then 0.5 * 20 + 0.5 * 10 = 15, then 0.5 * 40 + 0.5 * 15 = 27.5
+        assert_eq!(ask(&mut data, b'E', 3, 500_000), Some(28));
+        assert_eq!(ask(&mut data, b'E', 2, 500_000), Some(15));
+        assert_eq!(ask(&mut data, b'E', 3, 1_000_000), Some(40));
+        assert_eq!(ask(&mut data, b'E', 0, 500_000), Some(0));
+        assert_eq!(ask(&mut data, b'E', 3, 0), Some(-1));
+        assert_eq!(ask(&mut data, b'E', 3, 1_000_001), Some(-1));
+    }
+
+    #[test]
+    fn price_at_exact_timestamp() {
+        let mut data = history(&[(100, 42), (200, 7)]);
+        assert_eq!(ask(&mut data, b'T', 100, 0), Some(42));
+        assert_eq!(ask(&mut data, b'T', 150, 0), Some(0));
+    }
+
+    #[test]
+    fn delete_range() {
+        let mut data = history(&[(1, 10), (2, 20), (3, 30), (4, 40), (5, 50)]);
+        assert_eq!(ask(&mut data, b'D', 2, 4), None);
+        assert_eq!(ask(&mut data, b'N', 0, 0), Some(2));
+        assert_eq!(ask(&mut data, b'Q', 0, 10), Some(30));
+        // An inverted range deletes nothing
+        assert_eq!(ask(&mut data, b'D', 5, 1), None);
+        assert_eq!(ask(&mut data, b'N', 0, 0), Some(2));
+    }
+
+    #[test]
+    fn request_counts() {
+        let mut data = history(&[(1, 10), (2, 20)]);
+        ask(&mut data, b'D', 1, 1);
+        ask(&mut data, b'Q', 0, 10);
+        ask(&mut data, b'Q', 10, 0);
+        ask(&mut data, b'X', 0, 0);
+        let counts = &data.counts;
+        assert_eq!((counts.inserts, counts.deletes, counts.queries, counts.errors), (2, 1, 3, 2));
+    }
+
+    #[test]
+    fn standard_deviation() {
+        // Mean 5, population variance 4
+        let mut data = history(&[(1, 2), (2, 4), (3, 4), (4, 4), (5, 5), (6, 5), (7, 7), (8, 9)]);
+        assert_eq!(ask(&mut data, b'V', 1, 8), Some(2));
+        assert_eq!(ask(&mut data, b'V', 1, 1), Some(0));
+        assert_eq!(ask(&mut data, b'V', 8, 1), Some(0));
+    }
+
+    #[test]
+    fn median() {
+        let mut data = history(&[(1, 5), (2, 1), (3, 3), (4, 10)]);
+        assert_eq!(ask(&mut data, b'M', 1, 3), Some(3));
+        // The middle two are 3 and 5
+        assert_eq!(ask(&mut data, b'M', 1, 4), Some(4));
+        let mut data = history(&[(1, 2), (2, 3)]);
+        assert_eq!(ask(&mut data, b'M', 1, 2), Some(2));
+        let mut data = history(&[(1, -3), (2, -2)]);
+        assert_eq!(ask(&mut data, b'M', 1, 2), Some(-3));
+        assert_eq!(ask(&mut data, b'M', 5, 9), Some(0));
+    }
+
+    #[test]
+    fn time_weighted_average() {
+        // 10 holds for 90 seconds and 100 for the last 10, where the plain mean is 55
+        let mut data = history(&[(0, 10), (90, 100)]);
+        assert_eq!(ask(&mut data, b'Q', 0, 99), Some(55));
+        assert_eq!(ask(&mut data, b'W', 0, 99), Some(19));
+        // Time before the first price doesn't count
+        assert_eq!(ask(&mut data, b'W', -100, 99), Some(19));
+        assert_eq!(ask(&mut data, b'W', 90, 90), Some(100));
+        assert_eq!(ask(&mut data, b'W', 1, 89), Some(0));
+    }
+
+    #[tokio::test]
+    async fn serves_both_address_families() {
+        let v4 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let v6 = TcpListener::bind("[::1]:0").await.unwrap();
+        let addrs = [v4.local_addr().unwrap(), v6.local_addr().unwrap()];
+        tokio::spawn(serve(vec![Listener::from(v4), Listener::from(v6)], MeansConfig::default()));
+        for addr in addrs {
+            assert_eq!(exchange(addr, &[frame(b'I', 1, 42), frame(b'Q', 0, 1)], 1).await, [42]);
+        }
+    }
 }
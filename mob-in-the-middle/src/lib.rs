@@ -1,23 +1,67 @@
 use std::io;
 use std::net::SocketAddr;
-use std::sync::OnceLock;
-use tokio::net::TcpListener;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use common::Listener;
 use regex::bytes;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::select;
-use tracing::{debug, error, info};
+use tokio::time::sleep;
+use tracing::{debug, error, info, warn};
 
-const SERVER: (&str, u16) = ("chat.protohackers.com", 16963);
+/// The Protohackers budget chat server
+pub const DEFAULT_UPSTREAM: &str = "chat.protohackers.com:16963";
 
+/// Boguscoin addresses start with a 7 and are 26 to 35 alphanumeric characters long
+pub const DEFAULT_ADDRESS_PATTERN: &str = r"\b7\w{25,34}\b";
 
-pub async fn serve(address: SocketAddr) -> io::Result<()> {
-    let listener = TcpListener::bind(address).await?;
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// The chat server to proxy to, as `host:port`
+    pub upstream: String,
+    /// Log the addresses that would be rewritten, but forward everything unchanged
+    pub dry_run: bool,
+    /// Treat a bare `\r` as a line ending too, not just `\n` and `\r\n`
+    pub bare_cr_lines: bool,
+    /// How many times to try reconnecting to the upstream server if it drops, before giving up
+    /// on the client too
+    pub upstream_reconnects: u32,
+    /// Log every regex match, where it was in the line, and whether it was taken as an address
+    pub log_candidates: bool,
+    /// What an address looks like. A match only counts if it's a whole space-separated word.
+    pub address_pattern: bytes::Regex,
+    /// Lengths an address may be, on top of whatever the pattern requires
+    pub address_len: RangeInclusive<usize>,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            upstream: DEFAULT_UPSTREAM.to_string(),
+            dry_run: false,
+            bare_cr_lines: false,
+            upstream_reconnects: 0,
+            log_candidates: false,
+            address_pattern: address_pattern(DEFAULT_ADDRESS_PATTERN).expect("default pattern is valid"),
+            address_len: 26..=35,
+        }
+    }
+}
+
+/// Compile an address pattern, for `ProxyConfig::address_pattern`
+pub fn address_pattern(pattern: &str) -> Result<bytes::Regex, String> {
+    bytes::Regex::new(pattern).map_err(|e| e.to_string())
+}
+
+pub async fn serve(listener: Listener, config: ProxyConfig) -> io::Result<()> {
     loop {
         match listener.accept().await {
-            Ok((stream, addr)) => {
-                info!(client=%addr, "connection received");
-                tokio::spawn(handle(stream, addr));
+            Ok((stream, addr, permit)) => {
+                if listener.log_accept() {
+                    info!(client=%addr, "connection received");
+                }
+                tokio::spawn(permit.hold(handle(stream, addr, config.clone())));
             }
             Err(e) => {
                 error!(error=?e, "accept failed");
@@ -26,18 +70,24 @@ pub async fn serve(address: SocketAddr) -> io::Result<()> {
     }
 }
 
-fn transform_line(line: &[u8]) -> Vec<u8> {
+fn transform_line(line: &[u8], terminator: &[u8], config: &ProxyConfig) -> Vec<u8> {
     // No lookahead/lookbehind available, so we use this technique to look for spaces before/after
     // https://docs.rs/regex/latest/regex/struct.Regex.html#fallibility
-    static RE: OnceLock<bytes::Regex> = OnceLock::new();
     let mut replaced = Vec::new();
     let mut last_match = 0;
-    for cap in RE.get_or_init(|| bytes::Regex::new(r"\b7\w{25,34}\b").unwrap())
-        .captures_iter(line) {
+    for cap in config.address_pattern.captures_iter(line) {
         let m = cap.get(0).unwrap();
         replaced.extend_from_slice(&line[last_match..m.start()]);
-        if (m.start() == 0 || line[m.start() - 1] == b' ') &&
-            (m.end() == line.len() || line[m.end()] == b' ') {
+        let is_address = (m.start() == 0 || line[m.start() - 1] == b' ') &&
+            (m.end() == line.len() || line[m.end()] == b' ') &&
+            config.address_len.contains(&m.len());
+        if config.log_candidates {
+            info!(candidate=%String::from_utf8_lossy(m.as_bytes()), start=m.start(), end=m.end(), accepted=is_address, "boguscoin candidate");
+        }
+        if config.dry_run {
+            info!(candidate=%String::from_utf8_lossy(m.as_bytes()), rewrite=is_address, "dry run, not rewriting");
+            replaced.extend_from_slice(m.as_bytes());
+        } else if is_address {
             replaced.extend_from_slice(b"7YWHMfk9JZe0LM0g1ZauHuiSxhI");
         } else {
             // Not a real boguscoin address, leave as-is
@@ -46,35 +96,82 @@ fn transform_line(line: &[u8]) -> Vec<u8> {
         last_match = m.end();
     }
     replaced.extend_from_slice(&line[last_match..]);
-    replaced.push(b'\n');
+    replaced.extend_from_slice(terminator);
     replaced
 }
 
-fn next_line(b: &mut Vec<u8>) -> Option<Vec<u8>> {
-    if let Some(newline) = b.iter().position(|&c| c == b'\n') {
-        let line = b[..newline].to_vec();
-        b.drain(..newline+1);
-        Some(line)
-    } else {
-        None
+/// Split the next complete line off the buffer, returning it and the terminator it ended with
+fn next_line(b: &mut Vec<u8>, bare_cr: bool) -> Option<(Vec<u8>, &'static [u8])> {
+    let end = b.iter().position(|&c| c == b'\n' || (bare_cr && c == b'\r'))?;
+    let (len, terminator): (usize, &'static [u8]) = match b[end] {
+        b'\n' if end > 0 && b[end - 1] == b'\r' => (end - 1, b"\r\n"),
+        b'\n' => (end, b"\n"),
+        // Could be the first half of a CRLF, so wait to see what follows
+        _ if end + 1 == b.len() => return None,
+        _ if b[end + 1] == b'\n' => (end, b"\r\n"),
+        _ => (end, b"\r"),
+    };
+    let line = b[..len].to_vec();
+    b.drain(..len + terminator.len());
+    Some((line, terminator))
+}
+
+/// Try to reach the upstream again, doubling the wait between attempts. Each attempt uses up one
+/// of `attempts`, which is shared by the whole client connection.
+async fn reconnect(addr: SocketAddr, upstream: &str, attempts: &mut u32) -> Option<TcpStream> {
+    let mut backoff = Duration::from_millis(100);
+    while *attempts > 0 {
+        *attempts -= 1;
+        sleep(backoff).await;
+        match TcpStream::connect(upstream).await {
+            Ok(server) => {
+                info!(client=?addr, remaining=*attempts, "reconnected to server");
+                return Some(server);
+            }
+            Err(e) => {
+                warn!(client=?addr, error=%e, remaining=*attempts, "reconnect failed");
+                backoff *= 2;
+            }
+        }
     }
+    None
 }
 
-async fn handle(mut client: TcpStream, addr: SocketAddr) -> io::Result<()> {
-    let mut server = TcpStream::connect(SERVER).await?;
+async fn handle(mut client: TcpStream, addr: SocketAddr, config: ProxyConfig) -> io::Result<()> {
+    let mut server = TcpStream::connect(&config.upstream).await?;
     debug!(client=?addr, server=?server, "established server connection");
-    let (mut from_client, mut to_client) = client.split();
-    let (mut from_server, mut to_server) = server.split();
     let mut from_client_buf = Vec::with_capacity(1024);
     let mut from_server_buf = Vec::with_capacity(1024);
+    // Client lines the upstream hasn't taken yet, which go to the next connection if this one drops
+    let mut unsent = Vec::new();
+    let mut reconnects_left = config.upstream_reconnects;
+    // The client's first line is its name, which a new upstream connection has to be told again
+    let mut name: Option<Vec<u8>> = None;
+    let mut named = false;
+    // The first server line is its greeting, which the client shouldn't see twice
+    let mut greeting: Option<Vec<u8>> = None;
+    let mut skip_greeting = false;
     loop {
-        select! {
-            b = from_client.read_buf(&mut from_client_buf) => {
+        let lost_server = select! {
+            b = client.read_buf(&mut from_client_buf) => {
                 match b {
                     Ok(count) if count > 0 => {
-                        while let Some(line) = next_line(&mut from_client_buf) {
+                        while let Some((line, terminator)) = next_line(&mut from_client_buf, config.bare_cr_lines) {
                             debug!(client=?addr, line=%String::from_utf8_lossy(&line), "from client");
-                            to_server.write_all(&transform_line(&line)).await?;
+                            let line = transform_line(&line, terminator, &config);
+                            unsent.extend_from_slice(&line);
+                            name.get_or_insert(line);
+                        }
+                        match server.write_all(&unsent).await {
+                            Ok(()) => {
+                                unsent.clear();
+                                named = name.is_some();
+                                false
+                            }
+                            Err(e) => {
+                                error!(client=?addr, error=?e, "lost server connection");
+                                true
+                            }
                         }
                     }
                     _ => {
@@ -83,20 +180,220 @@ async fn handle(mut client: TcpStream, addr: SocketAddr) -> io::Result<()> {
                     }
                 }
             }
-            b = from_server.read_buf(&mut from_server_buf) => {
+            b = server.read_buf(&mut from_server_buf) => {
                 match b {
                     Ok(count) if count > 0 => {
-                        while let Some(line) = next_line(&mut from_server_buf) {
+                        while let Some((line, terminator)) = next_line(&mut from_server_buf, config.bare_cr_lines) {
                             debug!(client=?addr, line=%String::from_utf8_lossy(&line), "from server");
-                            to_client.write_all(&transform_line(&line)).await?;
+                            if skip_greeting {
+                                skip_greeting = false;
+                                if greeting.as_ref() == Some(&line) {
+                                    // The client has already been greeted and named itself
+                                    continue;
+                                }
+                            }
+                            greeting.get_or_insert_with(|| line.clone());
+                            client.write_all(&transform_line(&line, terminator, &config)).await?;
                         }
+                        false
                     }
                     _ => {
-                        error!(client=?addr, error=?b, "lost client connection");
-                        return Ok(())
+                        error!(client=?addr, error=?b, "lost server connection");
+                        true
                     }
                 }
             }
+        };
+        if lost_server {
+            if !from_server_buf.is_empty() {
+                // The old connection can never finish this line, so pass on what there is of it
+                warn!(client=?addr, partial=%String::from_utf8_lossy(&from_server_buf), "forwarding partial line from lost server");
+                let partial = std::mem::take(&mut from_server_buf);
+                client.write_all(&transform_line(&partial, b"\n", &config)).await?;
+            }
+            let Some(new_server) = reconnect(addr, &config.upstream, &mut reconnects_left).await else {
+                return Ok(())
+            };
+            server = new_server;
+            // If the old connection never took the name, it's still at the front of `unsent`
+            if let Some(name) = name.as_ref().filter(|_| named) {
+                server.write_all(name).await?;
+            }
+            skip_greeting = true;
+            server.write_all(&unsent).await?;
+            unsent.clear();
+            named = name.is_some();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    const ADDRESS: &str = "7F1u3wSD5RbOHQmupo9nx4TnhQ";
+    const TONY: &str = "7YWHMfk9JZe0LM0g1ZauHuiSxhI";
+
+    fn transform(line: &str, config: &ProxyConfig) -> String {
+        String::from_utf8(transform_line(line.as_bytes(), b"\n", config)).unwrap()
+    }
+
+    /// Log lines written on this thread
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Logs {
+        fn capture(&self) -> tracing::subscriber::DefaultGuard {
+            let logs = self.clone();
+            let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer(move || logs.clone()).finish();
+            tracing::subscriber::set_default(subscriber)
+        }
+
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).lines().map(str::to_string).collect()
+        }
+    }
+
+    #[test]
+    fn dry_run_leaves_addresses_alone() {
+        let line = format!("send to {ADDRESS} please");
+        assert_eq!(transform(&line, &ProxyConfig::default()), format!("send to {TONY} please\n"));
+        let logs = Logs::default();
+        let _guard = logs.capture();
+        let dry_run = ProxyConfig { dry_run: true, ..ProxyConfig::default() };
+        assert_eq!(transform(&line, &dry_run), format!("{line}\n"));
+        // It still says what it would have rewritten
+        let lines = logs.lines();
+        assert!(lines.iter().any(|l| l.contains("dry run, not rewriting") && l.contains(&format!("candidate={ADDRESS}")) && l.contains("rewrite=true")), "{lines:#?}");
+    }
+
+    #[test]
+    fn lines_keep_their_terminators() {
+        let mut buf = format!("{ADDRESS}\r\nhi {ADDRESS}\nbare {ADDRESS}\rpartial").into_bytes();
+        let config = ProxyConfig::default();
+        let (line, terminator) = next_line(&mut buf, false).unwrap();
+        assert_eq!(transform_line(&line, terminator, &config), format!("{TONY}\r\n").into_bytes());
+        let (line, terminator) = next_line(&mut buf, false).unwrap();
+        assert_eq!(transform_line(&line, terminator, &config), format!("hi {TONY}\n").into_bytes());
+        // A bare CR only ends a line if asked, and the rest waits for a terminator
+        assert_eq!(next_line(&mut buf, false), None);
+        let (line, terminator) = next_line(&mut buf, true).unwrap();
+        assert_eq!(transform_line(&line, terminator, &config), format!("bare {TONY}\r").into_bytes());
+        assert_eq!(next_line(&mut buf, true), None);
+        assert_eq!(buf, b"partial");
+    }
+
+    #[test]
+    fn cr_at_the_end_of_the_buffer_waits_for_what_follows() {
+        let mut buf = b"line\r".to_vec();
+        assert_eq!(next_line(&mut buf, true), None);
+        buf.push(b'\n');
+        assert_eq!(next_line(&mut buf, true), Some((b"line".to_vec(), &b"\r\n"[..])));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn candidates_inside_words_are_rejected() {
+        // The pattern matches after the hyphen, but it's not a whole space-separated word
+        let config = ProxyConfig { log_candidates: true, ..ProxyConfig::default() };
+        let line = format!("pay-{ADDRESS} or {ADDRESS}-now");
+        assert_eq!(transform(&line, &config), format!("{line}\n"));
+        assert_eq!(transform(&format!("pay {ADDRESS} now"), &config), format!("pay {TONY} now\n"));
+    }
+
+    #[test]
+    fn custom_address_pattern() {
+        let config = ProxyConfig {
+            address_pattern: address_pattern(r"\bBC-\d{8}\b").unwrap(),
+            address_len: 11..=11,
+            ..ProxyConfig::default()
+        };
+        assert_eq!(transform("send BC-12345678 now", &config), format!("send {TONY} now\n"));
+        assert_eq!(transform(&format!("send {ADDRESS} now"), &config), format!("send {ADDRESS} now\n"));
+        assert!(address_pattern("(unclosed").is_err());
+    }
+
+    #[tokio::test]
+    async fn reconnects_to_a_dropped_upstream() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let config = ProxyConfig { upstream: upstream.local_addr().unwrap().to_string(), upstream_reconnects: 1, ..ProxyConfig::default() };
+        tokio::spawn(serve(Listener::from(proxy), config));
+
+        let client = TcpStream::connect(proxy_addr).await.unwrap();
+        let (client_reader, mut client) = client.into_split();
+        let mut client_lines = BufReader::new(client_reader).lines();
+
+        // The first upstream connection greets the client, learns its name, and drops
+        let (mut server, _) = upstream.accept().await.unwrap();
+        server.write_all(b"Welcome\n").await.unwrap();
+        assert_eq!(client_lines.next_line().await.unwrap().as_deref(), Some("Welcome"));
+        client.write_all(b"alice\n").await.unwrap();
+        let mut server_lines = BufReader::new(server).lines();
+        assert_eq!(server_lines.next_line().await.unwrap().as_deref(), Some("alice"));
+        drop(server_lines);
+
+        // The second is told the name again, and its repeat of the greeting is kept from the client
+        let (server, _) = upstream.accept().await.unwrap();
+        let (server_reader, mut server) = server.into_split();
+        server.write_all(b"Welcome\n").await.unwrap();
+        let mut server_lines = BufReader::new(server_reader).lines();
+        assert_eq!(server_lines.next_line().await.unwrap().as_deref(), Some("alice"));
+        server.write_all(b"* in room: bob\n").await.unwrap();
+        assert_eq!(client_lines.next_line().await.unwrap().as_deref(), Some("* in room: bob"));
+        client.write_all(b"hi\n").await.unwrap();
+        assert_eq!(server_lines.next_line().await.unwrap().as_deref(), Some("hi"));
+    }
+
+    #[tokio::test]
+    async fn partial_lines_survive_a_dropped_upstream() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let config = ProxyConfig { upstream: upstream.local_addr().unwrap().to_string(), upstream_reconnects: 1, ..ProxyConfig::default() };
+        tokio::spawn(serve(Listener::from(proxy), config));
+
+        let client = TcpStream::connect(proxy_addr).await.unwrap();
+        let (client_reader, mut client) = client.into_split();
+        let mut client_lines = BufReader::new(client_reader).lines();
+
+        let (mut server, _) = upstream.accept().await.unwrap();
+        server.write_all(b"Welcome\n").await.unwrap();
+        assert_eq!(client_lines.next_line().await.unwrap().as_deref(), Some("Welcome"));
+        client.write_all(b"alice\n").await.unwrap();
+        let mut server_lines = BufReader::new(server).lines();
+        assert_eq!(server_lines.next_line().await.unwrap().as_deref(), Some("alice"));
+
+        // The upstream drops halfway through a line, and the client still gets what there was of it
+        let mut server = server_lines.into_inner().into_inner();
+        server.write_all(format!("[bob] pay {ADDRESS} and").as_bytes()).await.unwrap();
+        drop(server);
+        assert_eq!(client_lines.next_line().await.unwrap(), Some(format!("[bob] pay {TONY} and")));
+
+        // Sent while the proxy is reconnecting, so it goes to the new upstream after the name
+        client.write_all(b"hi\n").await.unwrap();
+        let (server, _) = upstream.accept().await.unwrap();
+        let (server_reader, mut server) = server.into_split();
+        server.write_all(b"Welcome\n").await.unwrap();
+        let mut server_lines = BufReader::new(server_reader).lines();
+        assert_eq!(server_lines.next_line().await.unwrap().as_deref(), Some("alice"));
+        assert_eq!(server_lines.next_line().await.unwrap().as_deref(), Some("hi"));
+        server.write_all(b"* in room: bob\n").await.unwrap();
+        assert_eq!(client_lines.next_line().await.unwrap().as_deref(), Some("* in room: bob"));
+    }
+}
@@ -1,20 +1,104 @@
 use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use common::{flush, send, ConnectionStats, Listener};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
 use tracing::{debug, error, info, info_span, warn};
 use serde::{Deserialize, Serialize};
 
+#[derive(Debug, Clone)]
+pub struct PrimeConfig {
+    /// Precompute primality of every number up to this, so small requests are a lookup
+    pub sieve_limit: usize,
+    /// Close connections after answering this many requests
+    pub max_requests_per_connection: Option<u64>,
+    /// Answer bad requests with a JSON error object, e.g. `{"error":"malformed"}`, instead of
+    /// the non-JSON `:P` and `:(`
+    pub json_errors: bool,
+}
+
+impl Default for PrimeConfig {
+    fn default() -> Self {
+        PrimeConfig {
+            sieve_limit: 1_000_000,
+            max_requests_per_connection: None,
+            json_errors: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Request {
+    pub method: String,
+    pub number: serde_json::Number,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Response {
+    pub method: String,
+    pub prime: bool,
+}
+
+/// Several numbers checked at once, e.g. `{"method":"isPrimeBatch","numbers":[2,4]}`
 #[derive(Debug, Clone, Deserialize)]
-struct Request {
-    method: String,
-    number: serde_json::Number,
+pub struct BatchRequest {
+    pub method: String,
+    pub numbers: Vec<serde_json::Number>,
 }
 
+/// One answer per number in the request, in the same order
 #[derive(Debug, Clone, Serialize)]
-struct Response {
-    method: String,
-    prime: bool,
+pub struct BatchResponse {
+    pub method: String,
+    pub primes: Vec<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum AnyRequest {
+    Single(Request),
+    Batch(BatchRequest),
+}
+
+struct Metrics {
+    requests_total: AtomicU64,
+    prime_total: AtomicU64,
+    composite_total: AtomicU64,
+    malformed_total: AtomicU64,
+    disconnects_total: AtomicU64,
+}
+
+static METRICS: Metrics = Metrics {
+    requests_total: AtomicU64::new(0),
+    prime_total: AtomicU64::new(0),
+    composite_total: AtomicU64::new(0),
+    malformed_total: AtomicU64::new(0),
+    disconnects_total: AtomicU64::new(0),
+};
+
+/// Request outcome counters, summed over all connections since startup
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct MetricsSnapshot {
+    pub requests_total: u64,
+    pub prime_total: u64,
+    pub composite_total: u64,
+    /// Requests that weren't valid JSON or weren't a well-formed `isPrime` or `isPrimeBatch` call
+    pub malformed_total: u64,
+    /// Connections closed, whether by the client or because of a malformed request
+    pub disconnects_total: u64,
+}
+
+pub fn metrics() -> MetricsSnapshot {
+    MetricsSnapshot {
+        requests_total: METRICS.requests_total.load(Ordering::Relaxed),
+        prime_total: METRICS.prime_total.load(Ordering::Relaxed),
+        composite_total: METRICS.composite_total.load(Ordering::Relaxed),
+        malformed_total: METRICS.malformed_total.load(Ordering::Relaxed),
+        disconnects_total: METRICS.disconnects_total.load(Ordering::Relaxed),
+    }
 }
 
 struct ResponseLine {
@@ -22,15 +106,27 @@ struct ResponseLine {
     disconnect: bool,
 }
 
-pub async fn serve(address: SocketAddr) -> io::Result<()> {
-    info!("starting");
-    let listener = TcpListener::bind(address).await?;
+/// Serve on every listener at once, e.g. one per address family
+pub async fn serve(listeners: Vec<Listener>, config: PrimeConfig) -> io::Result<()> {
+    info!(listeners=listeners.len(), "starting");
+    let sieve = Arc::new(Sieve::new(config.sieve_limit));
 
+    let mut accepting = JoinSet::new();
+    for listener in listeners {
+        accepting.spawn(accept_loop(listener, config.clone(), sieve.clone()));
+    }
+    while accepting.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn accept_loop(listener: Listener, config: PrimeConfig, sieve: Arc<Sieve>) {
     loop {
         match listener.accept().await {
-            Ok((stream, addr)) => {
-                info!(client=%addr, "connection received");
-                tokio::spawn(handle(stream, addr));
+            Ok((stream, addr, permit)) => {
+                if listener.log_accept() {
+                    info!(client=%addr, ipv6=addr.is_ipv6(), "connection received");
+                }
+                tokio::spawn(permit.hold(handle(stream, addr, config.clone(), sieve.clone())));
             }
             Err(e) => {
                 error!(error=?e, "accept failed");
@@ -39,44 +135,164 @@ pub async fn serve(address: SocketAddr) -> io::Result<()> {
     }
 }
 
-fn get_response(request: &Request) -> Option<Response> {
-    if request.method != "isPrime" {
-        return None
+/// Whether each number up to a limit is prime, one bit per number
+pub struct Sieve {
+    limit: usize,
+    /// Bit `n % 64` of word `n / 64` is set if `n` is prime
+    bits: Vec<u64>,
+}
+
+impl Sieve {
+    pub fn new(limit: usize) -> Sieve {
+        info!(limit=limit, "building sieve");
+        // Dividing first, as `limit + 1` would overflow for `usize::MAX`
+        let mut sieve = Sieve { limit, bits: vec![u64::MAX; limit / 64 + 1] };
+        sieve.clear(0);
+        sieve.clear(1);
+        let mut i = 2;
+        while i <= limit / i {
+            if sieve.bit(i) {
+                for multiple in (i * i..=limit).step_by(i) {
+                    sieve.clear(multiple);
+                }
+            }
+            i += 1;
+        }
+        sieve
     }
 
-    let prime = if let Some(n) = request.number.as_u64() {
-        if n == 0 || n == 1 {
-            false
+    /// Whether `n` is prime, or `None` if it's past the limit
+    pub fn get(&self, n: u64) -> Option<bool> {
+        let n = usize::try_from(n).ok().filter(|&n| n <= self.limit)?;
+        Some(self.bit(n))
+    }
+
+    fn bit(&self, n: usize) -> bool {
+        self.bits[n / 64] & (1 << (n % 64)) != 0
+    }
+
+    fn clear(&mut self, n: usize) {
+        self.bits[n / 64] &= !(1 << (n % 64));
+    }
+}
+
+fn is_prime_by_division(n: u64) -> bool {
+    if n == 0 || n == 1 {
+        false
+    } else {
+        let sqrt = (n as f64).sqrt().floor() as u64;
+        !(2..=sqrt).any(|x| n.is_multiple_of(x))
+    }
+}
+
+/// What sort of number a request asked about
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    NonNegInt(u64),
+    /// An integer too large for a u64, which serde_json can only hold as a float
+    BigInt,
+    Negative,
+    /// Anything else serde_json parsed as a float, including whole numbers written like `7.0`
+    Fractional,
+}
+
+pub fn number_kind(number: &serde_json::Number) -> Kind {
+    if let Some(n) = number.as_u64() {
+        Kind::NonNegInt(n)
+    } else if number.is_i64() {
+        Kind::Negative
+    } else {
+        let f = number.as_f64().expect("a Number that isn't an integer is a float");
+        if f < 0.0 {
+            Kind::Negative
+        } else if f.fract() == 0.0 && f >= u64::MAX as f64 {
+            Kind::BigInt
         } else {
-            let sqrt = (n as f64).sqrt().floor() as u64;
-            !(2..=sqrt).any(|x| n % x == 0)
+            Kind::Fractional
         }
-    } else {
-        warn!(request=?request, "non-i64");
-        false
-    };
+    }
+}
+
+/// Negative numbers, non-integers, and integers too large for a u64 are never prime. Numbers
+/// within the sieve are looked up, and anything bigger is checked by trial division.
+pub fn is_prime(number: &serde_json::Number, sieve: &Sieve) -> bool {
+    match number_kind(number) {
+        Kind::NonNegInt(n) => sieve.get(n).unwrap_or_else(|| is_prime_by_division(n)),
+        Kind::BigInt => {
+            // We can't test it, and it's probably a mistake anyway
+            warn!(number=%number, "integer too large to check");
+            false
+        }
+        Kind::Negative => {
+            debug!(number=%number, "negative");
+            false
+        }
+        Kind::Fractional => {
+            debug!(number=%number, "not an integer");
+            false
+        }
+    }
+}
+
+/// Returns `None` for requests that aren't a well-formed `isPrime` call
+pub fn handle_request(request: &Request, sieve: &Sieve) -> Option<Response> {
+    if request.method != "isPrime" {
+        return None
+    }
 
     Some(Response {
         method: "isPrime".to_string(),
-        prime
+        prime: is_prime(&request.number, sieve),
     })
 }
 
-fn get_response_line(request_line: &str) -> ResponseLine {
-    match serde_json::from_str::<Request>(request_line) {
+/// Returns `None` for requests that aren't a well-formed `isPrimeBatch` call
+pub fn handle_batch_request(request: &BatchRequest, sieve: &Sieve) -> Option<BatchResponse> {
+    if request.method != "isPrimeBatch" {
+        return None
+    }
+
+    Some(BatchResponse {
+        method: "isPrimeBatch".to_string(),
+        primes: request.numbers.iter().map(|number| is_prime(number, sieve)).collect(),
+    })
+}
+
+/// The line sent before disconnecting a client that sent a bad request
+fn error_line(config: &PrimeConfig, sentinel: &str, error: &str) -> String {
+    if config.json_errors {
+        serde_json::json!({ "error": error }).to_string()
+    } else {
+        sentinel.to_string()
+    }
+}
+
+fn get_response_line(request_line: &str, config: &PrimeConfig, sieve: &Sieve) -> ResponseLine {
+    METRICS.requests_total.fetch_add(1, Ordering::Relaxed);
+    let response_line = match serde_json::from_str::<AnyRequest>(request_line) {
         Ok(r) => {
             debug!(request=?r, "request");
-            match get_response(&r) {
+            let response = match r {
+                AnyRequest::Single(ref single) => handle_request(single, sieve)
+                    .map(|r| (vec![r.prime], serde_json::to_string(&r).unwrap())),
+                AnyRequest::Batch(ref batch) => handle_batch_request(batch, sieve)
+                    .map(|r| (r.primes.clone(), serde_json::to_string(&r).unwrap())),
+            };
+            match response {
                 None => {
                     warn!(request=?r, "bad request");
                     ResponseLine {
-                        line: ":(".to_string(),
+                        line: error_line(config, ":(", "bad request"),
                         disconnect: true,
                     }
                 }
-                Some(r) => {
+                Some((primes, line)) => {
+                    for prime in primes {
+                        let counter = if prime { &METRICS.prime_total } else { &METRICS.composite_total };
+                        counter.fetch_add(1, Ordering::Relaxed);
+                    }
                     ResponseLine {
-                        line: serde_json::to_string(&r).unwrap(),
+                        line,
                         disconnect: false
                     }
                 }
@@ -85,50 +301,290 @@ fn get_response_line(request_line: &str) -> ResponseLine {
         Err(e) => {
             error!(error=%e, "malformed request");
             ResponseLine {
-                line: ":P".to_string(),
+                line: error_line(config, ":P", "malformed"),
                 disconnect: true
             }
         }
+    };
+    if response_line.disconnect {
+        METRICS.malformed_total.fetch_add(1, Ordering::Relaxed);
     }
+    response_line
 }
 
-async fn handle(stream: TcpStream, addr: SocketAddr) {
+async fn handle(stream: TcpStream, addr: SocketAddr, config: PrimeConfig, sieve: Arc<Sieve>) {
     let (reader, mut writer) = stream.into_split();
     let mut buf_reader = BufReader::new(reader);
     let mut line = String::new();
     let mut connected = true;
     let span = info_span!("connection", client=%addr);
+    let mut stats = ConnectionStats::new();
+    let mut requests: u64 = 0;
     while connected {
+        // read_line keeps appending to `line` across however many segments a request arrives in,
+        // and only returns once it has a newline, so requests are never answered early. The one
+        // exception is an unterminated request followed by EOF, which is answered as the last line.
         match buf_reader.read_line(&mut line).await {
             Ok(n) => {
+                stats.read(n);
                 debug!(client=%addr, bytes=n, line=line, "read ok");
                 if n == 0 {
                     connected = false;
                 } else {
-                    let response_line = span.in_scope(|| get_response_line(&line));
+                    requests += 1;
+                    let response_line = span.in_scope(|| get_response_line(&line, &config, &sieve));
                     let mut line = response_line.line;
                     line.push('\n');
 
-                    match writer.write_all(line.as_bytes()).await {
+                    match send(&mut writer, line.as_bytes()).await {
                         Ok(()) => {
+                            stats.wrote(line.len());
                             info!(client=%addr, line=line, "write ok");
                         }
-                        Err(e) => {
-                            info!(client=%addr, line=line, error=?e, "write failed");
+                        Err(_) => {
+                            warn!(client=%addr, line=line, "closing after failed write");
                             connected = false;
                         }
                     }
                     if response_line.disconnect {
                         warn!(client=%addr, "disconnecting");
                         connected = false;
+                    } else if config.max_requests_per_connection.is_some_and(|max| requests >= max) {
+                        warn!(client=%addr, requests=requests, "request limit reached, disconnecting");
+                        connected = false;
                     }
                 }
                 line.clear();
             }
             Err(e) => {
                 warn!(client=%addr, error=%e, "read failed");
+                connected = false;
             }
         }
     }
-    info!(client=%addr, "disconnect");
+    // Send a FIN after everything we've written, e.g. a final `:P`, rather than leaving the close to
+    // the drop
+    if flush(&mut writer).await.is_ok() {
+        if let Err(e) = writer.shutdown().await {
+            debug!(client=%addr, error=%e, "shutdown failed");
+        }
+    }
+    METRICS.disconnects_total.fetch_add(1, Ordering::Relaxed);
+    info!(client=%addr, duration=?stats.duration(), bytes_read=stats.bytes_read, bytes_written=stats.bytes_written, "disconnect");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    /// Log lines written on this thread, which is every task's in a single-threaded test
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Logs {
+        fn capture(&self) -> tracing::subscriber::DefaultGuard {
+            let logs = self.clone();
+            let subscriber = tracing_subscriber::fmt().with_ansi(false).with_max_level(tracing::Level::DEBUG).with_writer(move || logs.clone()).finish();
+            tracing::subscriber::set_default(subscriber)
+        }
+
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).lines().map(str::to_string).collect()
+        }
+
+        /// The first line containing `needle`, giving tasks a moment to log it
+        async fn wait_for(&self, needle: &str) -> String {
+            for _ in 0..500 {
+                if let Some(line) = self.lines().into_iter().find(|l| l.contains(needle)) {
+                    return line;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("never logged {needle:?}: {:#?}", self.lines());
+        }
+    }
+
+    /// The value logged for `name` in a log line
+    fn field(line: &str, name: &str) -> String {
+        line.split(&format!(" {name}=")).nth(1).unwrap().split(' ').next().unwrap().to_string()
+    }
+
+    async fn start(config: PrimeConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(vec![Listener::from(listener)], config));
+        addr
+    }
+
+    /// Send `requests` on a new connection, and read everything sent back until it's closed
+    async fn exchange(addr: SocketAddr, requests: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(requests.as_bytes()).await.unwrap();
+        let mut replies = String::new();
+        timeout(Duration::from_secs(5), stream.read_to_string(&mut replies)).await.expect("still open").unwrap();
+        replies
+    }
+
+    #[tokio::test]
+    async fn request_split_across_segments_is_answered_once() {
+        let addr = start(PrimeConfig::default()).await;
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        stream.set_nodelay(true).unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        writer.write_all(br#"{"method":"isPrime","#).await.unwrap();
+        assert!(timeout(Duration::from_millis(200), lines.next_line()).await.is_err(), "answered half a request");
+
+        writer.write_all(br#""number":7}"#).await.unwrap();
+        writer.write_all(b"\n").await.unwrap();
+        let reply = timeout(Duration::from_secs(5), lines.next_line()).await.unwrap().unwrap();
+        assert_eq!(reply.as_deref(), Some(r#"{"method":"isPrime","prime":true}"#));
+        assert!(timeout(Duration::from_millis(200), lines.next_line()).await.is_err(), "answered more than once");
+    }
+
+    fn number(json: &str) -> serde_json::Number {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// Big enough for the numbers the tests ask about to be looked up, unless they're meant not to be
+    fn sieve() -> Sieve {
+        Sieve::new(10_000)
+    }
+
+    #[test]
+    fn primes() {
+        let sieve = sieve();
+        for n in ["2", "3", "5", "7919", "1000003", "1000000007"] {
+            assert!(is_prime(&number(n), &sieve), "{n}");
+        }
+        for n in ["0", "1", "4", "7917", "1000001", "-7", "7.5"] {
+            assert!(!is_prime(&number(n), &sieve), "{n}");
+        }
+    }
+
+    #[test]
+    fn outcome_counters() {
+        // Other tests share the counters, so only check these ones moved at least as far as they should
+        let before = metrics();
+        let config = PrimeConfig::default();
+        let sieve = sieve();
+        assert!(!get_response_line(r#"{"method":"isPrime","number":7}"#, &config, &sieve).disconnect);
+        assert!(!get_response_line(r#"{"method":"isPrime","number":8}"#, &config, &sieve).disconnect);
+        assert!(get_response_line("{", &config, &sieve).disconnect);
+        let after = metrics();
+        assert!(after.requests_total >= before.requests_total + 3);
+        assert!(after.prime_total > before.prime_total);
+        assert!(after.composite_total > before.composite_total);
+        assert!(after.malformed_total > before.malformed_total);
+    }
+
+    #[test]
+    fn sieve_matches_division() {
+        let sieve = sieve();
+        for n in 0..=10_000 {
+            assert_eq!(sieve.get(n), Some(is_prime_by_division(n)), "{n}");
+        }
+        assert_eq!(sieve.get(10_001), None);
+    }
+
+    #[test]
+    fn sieve_stops_at_its_limit() {
+        // Numbers past a small limit are still answered, by division
+        let sieve = Sieve::new(100);
+        assert_eq!(sieve.get(97), Some(true));
+        assert_eq!(sieve.get(101), None);
+        assert!(is_prime(&number("101"), &sieve));
+        assert!(!is_prime(&number("1001"), &sieve));
+        assert_eq!(Sieve::new(0).get(0), Some(false));
+        assert_eq!(Sieve::new(1).get(2), None);
+    }
+
+    #[tokio::test]
+    async fn disconnect_is_logged_with_stats() {
+        let logs = Logs::default();
+        let _guard = logs.capture();
+        let addr = start(PrimeConfig::default()).await;
+        let request = "{\"method\":\"isPrime\",\"number\":7}\n";
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        stream.shutdown().await.unwrap();
+        // Wait for the server to close its side too
+        let mut reply = String::new();
+        timeout(Duration::from_secs(5), stream.read_to_string(&mut reply)).await.expect("still open").unwrap();
+
+        let line = logs.wait_for(" disconnect ").await;
+        assert_eq!(field(&line, "bytes_read"), request.len().to_string());
+        assert_eq!(field(&line, "bytes_written"), reply.len().to_string());
+        assert_ne!(field(&line, "duration"), "0ns");
+    }
+
+    #[tokio::test]
+    async fn connection_closes_after_request_limit() {
+        let addr = start(PrimeConfig { max_requests_per_connection: Some(2), ..PrimeConfig::default() }).await;
+        let replies = exchange(addr, "{\"method\":\"isPrime\",\"number\":2}\n".repeat(3).as_str()).await;
+        assert_eq!(replies, "{\"method\":\"isPrime\",\"prime\":true}\n".repeat(2));
+    }
+
+    #[test]
+    fn error_replies() {
+        let sentinels = PrimeConfig::default();
+        let json = PrimeConfig { json_errors: true, ..PrimeConfig::default() };
+        let malformed = "not json";
+        let bad_request = r#"{"method":"isComposite","number":7}"#;
+        for (request, config, expected) in [
+            (malformed, &sentinels, ":P"),
+            (bad_request, &sentinels, ":("),
+            (malformed, &json, r#"{"error":"malformed"}"#),
+            (bad_request, &json, r#"{"error":"bad request"}"#),
+        ] {
+            let response = get_response_line(request, config, &sieve());
+            assert_eq!(response.line, expected);
+            assert!(response.disconnect);
+        }
+    }
+
+    #[tokio::test]
+    async fn sentinel_arrives_before_close() {
+        let addr = start(PrimeConfig::default()).await;
+        let replies = exchange(addr, "{\"method\":\"isPrime\",\"number\":4}\nnonsense\n{\"method\":\"isPrime\",\"number\":3}\n").await;
+        assert_eq!(replies, "{\"method\":\"isPrime\",\"prime\":false}\n:P\n");
+    }
+
+    #[test]
+    fn batch_request() {
+        let response = get_response_line(r#"{"method":"isPrimeBatch","numbers":[2,4,7,9.5,-3,13]}"#, &PrimeConfig::default(), &sieve());
+        assert_eq!(response.line, r#"{"method":"isPrimeBatch","primes":[true,false,true,false,false,true]}"#);
+        assert!(!response.disconnect);
+        let response = get_response_line(r#"{"method":"isPrimeBatch","numbers":7}"#, &PrimeConfig::default(), &sieve());
+        assert!(response.disconnect);
+    }
+
+    #[test]
+    fn number_kinds() {
+        assert_eq!(number_kind(&number("7")), Kind::NonNegInt(7));
+        assert_eq!(number_kind(&number("-7")), Kind::Negative);
+        assert_eq!(number_kind(&number("-0.5")), Kind::Negative);
+        assert_eq!(number_kind(&number("7.5")), Kind::Fractional);
+        assert_eq!(number_kind(&number("7.0")), Kind::Fractional);
+        assert_eq!(number_kind(&number("1e30")), Kind::BigInt);
+        assert_eq!(number_kind(&number("18446744073709551615")), Kind::NonNegInt(u64::MAX));
+    }
 }
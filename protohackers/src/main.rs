@@ -1,10 +1,28 @@
-use clap::{Parser, ValueEnum};
-use std::net::SocketAddr;
-
+use clap::{ArgAction, Parser, ValueEnum};
+use color_eyre::eyre::eyre;
+use nix::unistd::{setgid, setgroups, setuid, Group, User};
+use regex::bytes::Regex;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::PathBuf;
+use std::time::Duration;
 
+use budget_chat::{ChatConfig, FloodControl, MinThroughput};
+use common::{Cidr, ListenOptions, SourceFilter};
+use means_to_an_end::MeansConfig;
+use mob_in_the_middle::ProxyConfig;
+use prime_time::PrimeConfig;
+use smoke_test::EchoConfig;
+use speed_daemon::{Daemon, DaemonConfig};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::runtime::Builder;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
 use tracing_subscriber::EnvFilter;
+use unusual_database_program::{DatabaseConfig, RateLimit};
 
-#[derive(Copy, Clone, Debug, ValueEnum)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
 enum Problem {
     SmokeTest,
     PrimeTime,
@@ -15,19 +33,568 @@ enum Problem {
     SpeedDaemon,
 }
 
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum EchoTransform {
+    Identity,
+    Uppercase,
+    Lowercase,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum LimitComparison {
+    /// Faster than the limit plus --speed-tolerance
+    OverTolerance,
+    /// Faster than the limit
+    Over,
+    /// At the limit or faster
+    AtOrOver,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum PendingTicketPolicy {
+    /// Drop the longest-waiting ticket
+    DropOldest,
+    /// Don't issue new tickets
+    StopIssuing,
+}
+
 #[derive(Parser, Debug)]
 struct Args {
-    /// Host and port to listen on
-    #[arg(short, long, default_value = "0.0.0.0:32767")]
-    listen: SocketAddr,
+    /// Host and port to listen on, either for every problem or for just one as PROBLEM=ADDR,
+    /// e.g. speed-daemon=0.0.0.0:9001 (may be repeated) [default: 0.0.0.0:32767]
+    #[arg(short, long, value_parser = parse_listen)]
+    listen: Vec<(Option<Problem>, SocketAddr)>,
+
+    /// Further addresses to listen on alongside --listen, e.g. [::]:32767 for dual-stack.
+    /// Only prime-time and means-to-an-end use these.
+    #[arg(long)]
+    also_listen: Vec<SocketAddr>,
 
     /// Problem to run
     #[arg(short, long, default_value = "speed-daemon")]
     problem: Problem,
+
+    /// Set SO_REUSEADDR on TCP listeners
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    reuse_addr: bool,
+
+    /// Maximum number of pending connections on TCP listeners
+    #[arg(long, default_value_t = 1024)]
+    backlog: u32,
+
+    /// Only accept TCP clients from these networks, e.g. 10.0.0.0/8 (may be repeated)
+    #[arg(long)]
+    allow: Vec<Cidr>,
+
+    /// Refuse TCP clients from these networks, even if allowed (may be repeated)
+    #[arg(long)]
+    deny: Vec<Cidr>,
+
+    /// Log only every Nth TCP connection accepted, to keep busy servers' logs readable
+    #[arg(long, default_value = "1")]
+    log_every_accept: NonZeroU64,
+
+    /// Most TCP connections one source IP may have open at once
+    #[arg(long)]
+    max_connections_per_ip: Option<usize>,
+
+    /// Probe TCP connections idle for this many seconds with keepalives, so dead peers get
+    /// disconnected
+    #[arg(long)]
+    keepalive_secs: Option<u64>,
+
+    /// Tokio worker threads; 0 runs everything on the main thread, which makes task
+    /// interleaving repeatable and is handy for reproducing ordering bugs. Defaults to one per CPU.
+    #[arg(long)]
+    worker_threads: Option<usize>,
+
+    /// Switch to this user once listening, e.g. after binding a privileged port as root
+    #[arg(long)]
+    user: Option<String>,
+
+    /// Switch to this group once listening; defaults to the --user's primary group
+    #[arg(long)]
+    group: Option<String>,
+
+    #[command(flatten)]
+    echo: EchoArgs,
+
+    #[command(flatten)]
+    prime: PrimeArgs,
+
+    #[command(flatten)]
+    means: MeansArgs,
+
+    #[command(flatten)]
+    chat: ChatArgs,
+
+    #[command(flatten)]
+    database: DatabaseArgs,
+
+    #[command(flatten)]
+    proxy: ProxyArgs,
+
+    #[command(flatten)]
+    daemon: DaemonArgs,
+}
+
+const DEFAULT_LISTEN: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 32767);
+
+fn parse_listen(s: &str) -> Result<(Option<Problem>, SocketAddr), String> {
+    match s.split_once('=') {
+        Some((problem, address)) => {
+            let problem = Problem::from_str(problem, false)?;
+            let address = address.parse().map_err(|e| format!("invalid address: {e}"))?;
+            Ok((Some(problem), address))
+        }
+        None => Ok((None, s.parse().map_err(|e| format!("invalid address: {e}"))?)),
+    }
+}
+
+impl Args {
+    /// Where to listen for `problem`: its own --listen if it has one, else the last general one
+    fn listen_address(&self, problem: Problem) -> SocketAddr {
+        let last = |general: bool| self.listen.iter().rev()
+            .find(|(p, _)| if general { p.is_none() } else { *p == Some(problem) })
+            .map(|&(_, address)| address);
+        last(false).or_else(|| last(true)).unwrap_or(DEFAULT_LISTEN)
+    }
+}
+
+#[derive(clap::Args, Debug)]
+#[command(next_help_heading = "Smoke Test")]
+struct EchoArgs {
+    /// Transform applied to data before it's echoed back
+    #[arg(long, default_value = "identity")]
+    transform: EchoTransform,
+
+    /// Banner sent to each client on connect
+    #[arg(long)]
+    banner: Option<String>,
+
+    /// Wait this many milliseconds before echoing data back
+    #[arg(long, default_value_t = 0)]
+    response_delay_ms: u64,
+
+    /// Echo data back in writes of at most this many bytes
+    #[arg(long)]
+    chunk_size: Option<NonZeroUsize>,
+}
+
+impl EchoArgs {
+    fn config(&self) -> EchoConfig {
+        EchoConfig {
+            transform: match self.transform {
+                EchoTransform::Identity => smoke_test::identity,
+                EchoTransform::Uppercase => smoke_test::uppercase,
+                EchoTransform::Lowercase => smoke_test::lowercase,
+            },
+            banner: self.banner.as_ref().map(|b| b.as_bytes().to_vec()),
+            response_delay: Duration::from_millis(self.response_delay_ms),
+            chunk_size: self.chunk_size,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+#[command(next_help_heading = "Prime Time")]
+struct PrimeArgs {
+    /// Precompute which numbers up to this are prime at startup
+    #[arg(long, default_value_t = 1_000_000)]
+    sieve_limit: usize,
+
+    /// Close connections after answering this many requests
+    #[arg(long)]
+    max_requests_per_connection: Option<u64>,
+
+    /// Answer bad requests with a JSON error object rather than a non-JSON sentinel
+    #[arg(long)]
+    json_errors: bool,
+}
+
+impl PrimeArgs {
+    fn config(&self) -> PrimeConfig {
+        PrimeConfig {
+            sieve_limit: self.sieve_limit,
+            max_requests_per_connection: self.max_requests_per_connection,
+            json_errors: self.json_errors,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+#[command(next_help_heading = "Means to an End")]
+struct MeansArgs {
+    /// Close connections that send nothing for this many seconds
+    #[arg(long, default_value_t = 300)]
+    read_timeout_secs: u64,
+
+    /// Reply to queries whose end is before their start with this value
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    inverted_range_reply: i32,
+
+    /// Keep up to this many price histories for clients to resume with an `S` frame
+    #[arg(long)]
+    max_sessions: Option<NonZeroUsize>,
+
+    /// Expect each request to be preceded by a two-byte length
+    #[arg(long)]
+    length_prefixed: bool,
+}
+
+impl MeansArgs {
+    fn config(&self) -> MeansConfig {
+        MeansConfig {
+            read_timeout: Duration::from_secs(self.read_timeout_secs),
+            inverted_range_reply: self.inverted_range_reply,
+            max_sessions: self.max_sessions,
+            length_prefixed: self.length_prefixed,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+#[command(next_help_heading = "Budget Chat")]
+struct ChatArgs {
+    /// End server-generated lines with CRLF instead of LF
+    #[arg(long)]
+    crlf: bool,
+
+    /// Don't tell joining users who else is in the room
+    #[arg(long)]
+    hide_roster: bool,
+
+    /// Disconnect clients with more than this many lines waiting to be written
+    #[arg(long, default_value_t = 128)]
+    send_queue_capacity: usize,
+
+    /// Number broadcast messages, e.g. `#42 [alice] hi`
+    #[arg(long)]
+    sequence_numbers: bool,
+
+    /// Turn away new connections while this many clients are connected
+    #[arg(long)]
+    max_clients: Option<usize>,
+
+    /// Disconnect clients sending less than this many bytes of an unfinished line per window
+    #[arg(long)]
+    min_throughput_bytes: Option<u64>,
+
+    /// Window for --min-throughput-bytes, in seconds
+    #[arg(long, default_value_t = 60)]
+    min_throughput_window_secs: u64,
+
+    /// Line sent to ask new clients for a nick
+    #[arg(long, default_value = "enter nick")]
+    prompt: String,
+
+    /// Message of the day, sent to clients after they join
+    #[arg(long)]
+    motd: Option<String>,
+
+    /// Don't let anyone use this nick, e.g. server (may be repeated)
+    #[arg(long)]
+    reserved_nick: Vec<String>,
+
+    /// Cut chat messages longer than this many characters short
+    #[arg(long)]
+    max_message_len: Option<usize>,
+
+    /// Drop chat messages beyond this many per --flood-window-secs from one client
+    #[arg(long)]
+    flood_messages: Option<u32>,
+
+    /// Window for --flood-messages, in seconds
+    #[arg(long, default_value_t = 10)]
+    flood_window_secs: u64,
+
+    /// Mute clients that go over --flood-messages this many times in --flood-strike-window-secs
+    #[arg(long, default_value_t = 3)]
+    flood_strikes: usize,
+
+    /// Window for --flood-strikes, in seconds
+    #[arg(long, default_value_t = 60)]
+    flood_strike_window_secs: u64,
+
+    /// How long flooding clients are muted for, in seconds
+    #[arg(long, default_value_t = 60)]
+    flood_mute_secs: u64,
+}
+
+impl ChatArgs {
+    fn config(&self) -> ChatConfig {
+        ChatConfig {
+            crlf: self.crlf,
+            reveal_roster: !self.hide_roster,
+            prompt: self.prompt.clone(),
+            motd: self.motd.clone(),
+            reserved_nicks: self.reserved_nick.iter().cloned().collect(),
+            max_message_len: self.max_message_len,
+            on_join: None,
+            on_leave: None,
+            flood_control: self.flood_messages.map(|messages| FloodControl {
+                messages,
+                window: Duration::from_secs(self.flood_window_secs),
+                strikes: self.flood_strikes,
+                strike_window: Duration::from_secs(self.flood_strike_window_secs),
+                mute: Duration::from_secs(self.flood_mute_secs),
+            }),
+            send_queue_capacity: self.send_queue_capacity,
+            sequence_numbers: self.sequence_numbers,
+            max_clients: self.max_clients,
+            min_throughput: self.min_throughput_bytes.map(|bytes| MinThroughput {
+                bytes,
+                window: Duration::from_secs(self.min_throughput_window_secs),
+            }),
+        }
+    }
 }
 
-#[tokio::main]
-async fn main() -> color_eyre::Result<()> {
+#[derive(clap::Args, Debug)]
+#[command(next_help_heading = "Unusual Database Program")]
+struct DatabaseArgs {
+    /// Largest datagram accepted, in bytes
+    #[arg(long, default_value_t = 1000)]
+    max_packet: usize,
+
+    /// Skip repeated identical inserts from the same source within this many milliseconds
+    #[arg(long)]
+    dedup_window_ms: Option<u64>,
+
+    /// Maximum sustained datagrams per second from each source IP
+    #[arg(long)]
+    rate_limit: Option<f64>,
+
+    /// Datagrams a source IP may send in a burst before being rate limited
+    #[arg(long, default_value_t = 100)]
+    rate_limit_burst: u32,
+
+    /// Maximum number of source IPs tracked by the rate limiter
+    #[arg(long, default_value = "10000")]
+    rate_limit_sources: NonZeroUsize,
+
+    /// Reply `key=` when a retrieved key isn't set
+    #[arg(long)]
+    reply_on_miss: bool,
+
+    /// Reply `error=packet too large` to datagrams over --max-packet
+    #[arg(long)]
+    reply_oversized: bool,
+
+    /// Let this source IP retrieve the `dump` key to get every stored pair, and switch value
+    /// compression with `compress=on/off` (may be repeated)
+    #[arg(long)]
+    admin_source: Vec<IpAddr>,
+
+    /// Forward a copy of every insert to this peer, e.g. a standby instance
+    #[arg(long)]
+    replicate_to: Option<SocketAddr>,
+}
+
+impl DatabaseArgs {
+    fn config(&self) -> DatabaseConfig {
+        DatabaseConfig {
+            max_packet: self.max_packet,
+            dedup_window: self.dedup_window_ms.map(Duration::from_millis),
+            rate_limit: self.rate_limit.map(|per_second| RateLimit {
+                per_second,
+                burst: self.rate_limit_burst,
+                max_sources: self.rate_limit_sources,
+            }),
+            reply_on_miss: self.reply_on_miss,
+            reply_oversized: self.reply_oversized,
+            admin_sources: self.admin_source.clone(),
+            replicate_to: self.replicate_to,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+#[command(next_help_heading = "Mob in the Middle")]
+struct ProxyArgs {
+    /// Chat server to proxy to, as host:port
+    #[arg(long, default_value = mob_in_the_middle::DEFAULT_UPSTREAM)]
+    upstream: String,
+
+    /// Log addresses that would be rewritten without changing them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Also split lines on a bare CR, not just LF and CRLF
+    #[arg(long)]
+    bare_cr_lines: bool,
+
+    /// Times to try reconnecting to the upstream server when it drops a client's connection
+    #[arg(long, default_value_t = 0)]
+    upstream_reconnects: u32,
+
+    /// Log each possible address found, and whether it was rewritten
+    #[arg(long)]
+    log_candidates: bool,
+
+    /// Regex matching a boguscoin address
+    #[arg(long, default_value = mob_in_the_middle::DEFAULT_ADDRESS_PATTERN, value_parser = mob_in_the_middle::address_pattern)]
+    address_pattern: Regex,
+
+    /// Shortest match taken as an address
+    #[arg(long, default_value_t = 26)]
+    min_address_len: usize,
+
+    /// Longest match taken as an address
+    #[arg(long, default_value_t = 35)]
+    max_address_len: usize,
+}
+
+impl ProxyArgs {
+    fn config(&self) -> ProxyConfig {
+        ProxyConfig {
+            upstream: self.upstream.clone(),
+            dry_run: self.dry_run,
+            bare_cr_lines: self.bare_cr_lines,
+            upstream_reconnects: self.upstream_reconnects,
+            log_candidates: self.log_candidates,
+            address_pattern: self.address_pattern.clone(),
+            address_len: self.min_address_len..=self.max_address_len,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+#[command(next_help_heading = "Speed Daemon")]
+struct DaemonArgs {
+    /// Ticket cars going faster than the limit plus this many hundredths of a mph
+    #[arg(long, default_value_t = 10)]
+    speed_tolerance: u32,
+
+    /// When a car counts as speeding
+    #[arg(long, default_value = "over-tolerance")]
+    limit_comparison: LimitComparison,
+
+    /// Let cameras re-identify on the same road to move to a new mile
+    #[arg(long)]
+    allow_camera_moves: bool,
+
+    /// Enforce a stricter limit on a road, as ROAD=LIMIT (may be repeated)
+    #[arg(long, value_parser = parse_speed_limit_override)]
+    speed_limit_override: Vec<(u16, u16)>,
+
+    /// Reject dispatchers responsible for more than this many roads
+    #[arg(long, default_value_t = 255)]
+    max_dispatcher_roads: usize,
+
+    /// Serve a live feed of issued tickets, as JSON server-sent events, on this address
+    #[arg(long)]
+    ticket_feed: Option<SocketAddr>,
+
+    /// Ignore plate reports timestamped before this
+    #[arg(long)]
+    min_timestamp: Option<u32>,
+
+    /// Ignore plate reports timestamped after this
+    #[arg(long)]
+    max_timestamp: Option<u32>,
+
+    /// Hand out each road's tickets to its dispatchers in turn
+    #[arg(long)]
+    round_robin_dispatch: bool,
+
+    /// Reject heartbeat requests from clients that haven't identified themselves yet
+    #[arg(long)]
+    identify_before_heartbeat: bool,
+
+    /// Remember at most this many sightings of a plate on a road, forgetting the oldest
+    #[arg(long, default_value_t = 10_000)]
+    max_observations: usize,
+
+    /// Refuse heartbeats more frequent than this many milliseconds apart
+    #[arg(long)]
+    min_heartbeat_ms: Option<u64>,
+
+    /// Refuse heartbeats less frequent than this many milliseconds apart
+    #[arg(long)]
+    max_heartbeat_ms: Option<u64>,
+
+    /// Keep at most this many tickets waiting for a dispatcher
+    #[arg(long)]
+    max_pending_tickets: Option<NonZeroUsize>,
+
+    /// What to do when --max-pending-tickets are waiting
+    #[arg(long, default_value = "drop-oldest")]
+    pending_ticket_policy: PendingTicketPolicy,
+
+    /// Make cameras and dispatchers send this secret in an Authenticate (0x90) message before
+    /// identifying
+    #[arg(long)]
+    auth_secret: Option<String>,
+
+    /// Write every recorded observation to this CSV file each time we get SIGUSR2
+    #[arg(long)]
+    observations_csv: Option<PathBuf>,
+}
+
+fn parse_speed_limit_override(s: &str) -> Result<(u16, u16), String> {
+    let (road, limit) = s.split_once('=').ok_or("expected ROAD=LIMIT")?;
+    let road = road.parse().map_err(|e| format!("invalid road: {e}"))?;
+    let limit = limit.parse().map_err(|e| format!("invalid limit: {e}"))?;
+    Ok((road, limit))
+}
+
+impl DaemonArgs {
+    fn config(&self) -> DaemonConfig {
+        DaemonConfig {
+            speed_tolerance: self.speed_tolerance,
+            limit_comparison: match self.limit_comparison {
+                LimitComparison::OverTolerance => speed_daemon::LimitComparison::OverTolerance,
+                LimitComparison::Over => speed_daemon::LimitComparison::Over,
+                LimitComparison::AtOrOver => speed_daemon::LimitComparison::AtOrOver,
+            },
+            allow_camera_moves: self.allow_camera_moves,
+            speed_limit_overrides: self.speed_limit_override.iter().copied().collect(),
+            max_dispatcher_roads: self.max_dispatcher_roads,
+            on_ticket: None,
+            round_robin_dispatch: self.round_robin_dispatch,
+            heartbeat_before_identify: !self.identify_before_heartbeat,
+            max_observations_per_plate_road: self.max_observations,
+            max_pending_tickets: self.max_pending_tickets,
+            auth_secret: self.auth_secret.as_ref().map(|s| s.as_bytes().to_vec()),
+            pending_ticket_policy: match self.pending_ticket_policy {
+                PendingTicketPolicy::DropOldest => speed_daemon::PendingTicketPolicy::DropOldest,
+                PendingTicketPolicy::StopIssuing => speed_daemon::PendingTicketPolicy::StopIssuing,
+            },
+            heartbeat_range: match (self.min_heartbeat_ms, self.max_heartbeat_ms) {
+                (None, None) => None,
+                (min, max) => Some(Duration::from_millis(min.unwrap_or(0))..=max.map_or(Duration::MAX, Duration::from_millis)),
+            },
+            timestamp_range: match (self.min_timestamp, self.max_timestamp) {
+                (None, None) => None,
+                (min, max) => Some(min.unwrap_or(0)..=max.unwrap_or(u32::MAX)),
+            },
+        }
+    }
+}
+
+/// Give up root for the configured user and group. Call this only once the listeners are bound.
+fn drop_privileges(user: Option<&str>, group: Option<&str>) -> color_eyre::Result<()> {
+    let user = user
+        .map(|name| User::from_name(name)?.ok_or_else(|| eyre!("no such user: {name}")))
+        .transpose()?;
+    let group = group
+        .map(|name| Group::from_name(name)?.ok_or_else(|| eyre!("no such group: {name}")))
+        .transpose()?;
+    // The group has to change first, since we can't once we're no longer root
+    if let Some(gid) = group.as_ref().map(|g| g.gid).or(user.as_ref().map(|u| u.gid)) {
+        setgroups(&[gid])?;
+        setgid(gid)?;
+        info!(gid=%gid, "changed group");
+    }
+    if let Some(user) = user {
+        setuid(user.uid)?;
+        info!(uid=%user.uid, user=user.name, "changed user");
+    }
+    Ok(())
+}
+
+fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
 
     if std::env::var("RUST_LOG").is_err() {
@@ -40,15 +607,143 @@ async fn main() -> color_eyre::Result<()> {
 
     let args = Args::parse();
 
+    let mut runtime = match args.worker_threads {
+        Some(0) => Builder::new_current_thread(),
+        Some(threads) => {
+            let mut builder = Builder::new_multi_thread();
+            builder.worker_threads(threads);
+            builder
+        }
+        None => Builder::new_multi_thread(),
+    };
+    runtime.enable_all().build()?.block_on(run(args))
+}
+
+/// Start draining when we get SIGUSR1
+#[cfg(unix)]
+fn drain_on_signal() -> color_eyre::Result<()> {
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        usr1.recv().await;
+        common::drain();
+    });
+    Ok(())
+}
+
+/// Export speed-daemon's observations to `path` whenever we get SIGUSR2
+#[cfg(unix)]
+fn export_on_signal(daemon: Daemon, path: PathBuf) -> color_eyre::Result<()> {
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+    tokio::spawn(async move {
+        while usr2.recv().await.is_some() {
+            if let Err(e) = daemon.export_observations(&path).await {
+                error!(path=?path, error=%e, "exporting observations failed");
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn run(args: Args) -> color_eyre::Result<()> {
+    #[cfg(unix)]
+    drain_on_signal()?;
+    let listen_options = ListenOptions {
+        reuse_addr: args.reuse_addr,
+        backlog: args.backlog,
+        // Otherwise [::]:port also takes IPv4 connections, and clashes with 0.0.0.0:port
+        v6_only: !args.also_listen.is_empty(),
+        sources: SourceFilter {
+            allow: args.allow.clone(),
+            deny: args.deny.clone(),
+        },
+        accept_log_every: args.log_every_accept,
+        max_connections_per_ip: args.max_connections_per_ip,
+        keepalive: args.keepalive_secs.map(Duration::from_secs),
+    };
+    let listen = args.listen_address(args.problem);
+    let addresses: Vec<SocketAddr> = std::iter::once(listen).chain(args.also_listen.iter().copied()).collect();
+    let privileges = || drop_privileges(args.user.as_deref(), args.group.as_deref());
+    let tcp = || -> color_eyre::Result<_> {
+        let listener = common::bind(listen, &listen_options)?;
+        privileges()?;
+        Ok(listener)
+    };
+    let tcp_all = || -> color_eyre::Result<_> {
+        let listeners = common::bind_all(&addresses, &listen_options)?;
+        privileges()?;
+        Ok(listeners)
+    };
+
     match args.problem {
-        Problem::SmokeTest => smoke_test::serve(args.listen).await?,
-        Problem::PrimeTime => prime_time::serve(args.listen).await?,
-        Problem::MeansToAnEnd => means_to_an_end::serve(args.listen).await?,
-        Problem::BudgetChat => budget_chat::serve(args.listen).await?,
-        Problem::UnusualDatabaseProgram => unusual_database_program::serve(args.listen).await?,
-        Problem::MobInTheMiddle => mob_in_the_middle::serve(args.listen).await?,
-        Problem::SpeedDaemon => speed_daemon::serve(args.listen).await?,
+        Problem::SmokeTest => smoke_test::serve(tcp()?, args.echo.config()).await?,
+        Problem::PrimeTime => prime_time::serve(tcp_all()?, args.prime.config()).await?,
+        Problem::MeansToAnEnd => means_to_an_end::serve(tcp_all()?, args.means.config()).await?,
+        Problem::BudgetChat => budget_chat::serve(tcp()?, args.chat.config()).await?,
+        Problem::UnusualDatabaseProgram => {
+            let socket = UdpSocket::bind(listen).await?;
+            privileges()?;
+            unusual_database_program::serve(socket, args.database.config()).await?
+        }
+        Problem::MobInTheMiddle => mob_in_the_middle::serve(tcp()?, args.proxy.config()).await?,
+        Problem::SpeedDaemon => {
+            // Bound now, as it may be a privileged port too
+            let feed = match args.daemon.ticket_feed {
+                Some(address) => Some(TcpListener::bind(address).await?),
+                None => None,
+            };
+            let daemon = Daemon::new(args.daemon.config(), feed);
+            #[cfg(unix)]
+            if let Some(ref path) = args.daemon.observations_csv {
+                export_on_signal(daemon.clone(), path.clone())?;
+            }
+            speed_daemon::serve(tcp()?, daemon).await?
+        }
     };
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        Args::try_parse_from(std::iter::once("protohackers").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn user_and_group() {
+        let parsed = args(&["--user", "nobody", "--group", "nogroup"]);
+        assert_eq!(parsed.user.as_deref(), Some("nobody"));
+        assert_eq!(parsed.group.as_deref(), Some("nogroup"));
+        let parsed = args(&[]);
+        assert_eq!((parsed.user, parsed.group), (None, None));
+        // Without either there's nothing to drop, even when not running as root
+        drop_privileges(None, None).unwrap();
+        assert!(drop_privileges(Some("no-such-user-here"), None).is_err());
+    }
+
+    #[test]
+    fn worker_threads() {
+        assert_eq!(args(&[]).worker_threads, None);
+        assert_eq!(args(&["--worker-threads", "0"]).worker_threads, Some(0));
+        assert_eq!(args(&["--worker-threads", "4"]).worker_threads, Some(4));
+        assert!(Args::try_parse_from(["protohackers", "--worker-threads", "-1"]).is_err());
+    }
+
+    #[test]
+    fn listen_per_problem() {
+        let parsed = args(&["-l", "127.0.0.1:1000", "--listen", "speed-daemon=0.0.0.0:9001", "-l", "prime-time=[::1]:9002"]);
+        assert_eq!(parsed.listen_address(Problem::SpeedDaemon), "0.0.0.0:9001".parse().unwrap());
+        assert_eq!(parsed.listen_address(Problem::PrimeTime), "[::1]:9002".parse().unwrap());
+        assert_eq!(parsed.listen_address(Problem::SmokeTest), "127.0.0.1:1000".parse().unwrap());
+
+        // The last of each kind wins, and there's a default if no general address is given
+        let parsed = args(&["-l", "speed-daemon=0.0.0.0:9001", "-l", "speed-daemon=0.0.0.0:9003"]);
+        assert_eq!(parsed.listen_address(Problem::SpeedDaemon), "0.0.0.0:9003".parse().unwrap());
+        assert_eq!(parsed.listen_address(Problem::BudgetChat), DEFAULT_LISTEN);
+
+        assert!(parse_listen("no-such-problem=0.0.0.0:1").is_err());
+        assert!(parse_listen("speed-daemon=nowhere").is_err());
+    }
+}
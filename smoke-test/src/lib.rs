@@ -1,17 +1,59 @@
 use std::io;
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::time::Duration;
+use common::{ConnectionStats, Listener};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
 use tracing::{debug, error, info, warn};
 
-pub async fn serve(address: SocketAddr) -> io::Result<()> {
-    let listener = TcpListener::bind(address).await?;
+#[derive(Debug, Clone)]
+pub struct EchoConfig {
+    /// Applied to each chunk of received bytes before it's echoed back. Chunks are whatever a
+    /// single read returned, so a transform shouldn't rely on seeing whole lines.
+    pub transform: fn(&[u8]) -> Vec<u8>,
+    /// Sent to each client as soon as it connects, before any echoing
+    pub banner: Option<Vec<u8>>,
+    /// Wait this long before echoing each chunk, to simulate a slow server
+    pub response_delay: Duration,
+    /// Echo in writes of at most this many bytes, with Nagle's algorithm off so each one tends
+    /// to go out in its own segment, for testing how clients reassemble data
+    pub chunk_size: Option<NonZeroUsize>,
+}
+
+impl Default for EchoConfig {
+    fn default() -> Self {
+        EchoConfig {
+            transform: identity,
+            banner: None,
+            response_delay: Duration::ZERO,
+            chunk_size: None,
+        }
+    }
+}
+
+pub fn identity(data: &[u8]) -> Vec<u8> {
+    data.to_vec()
+}
+
+pub fn uppercase(data: &[u8]) -> Vec<u8> {
+    data.to_ascii_uppercase()
+}
+
+pub fn lowercase(data: &[u8]) -> Vec<u8> {
+    data.to_ascii_lowercase()
+}
+
+pub async fn serve(listener: Listener, config: EchoConfig) -> io::Result<()> {
     info!("starting");
     loop {
         match listener.accept().await {
-            Ok((stream, addr)) => {
-                info!(client=%addr, "connection received");
-                tokio::spawn(handle(stream, addr));
+            Ok((stream, addr, permit)) => {
+                if listener.log_accept() {
+                    info!(client=%addr, "connection received");
+                }
+                tokio::spawn(permit.hold(handle(stream, addr, config.clone())));
             }
             Err(e) => {
                 error!(error=?e, "accept failed");
@@ -20,18 +62,50 @@ pub async fn serve(address: SocketAddr) -> io::Result<()> {
     }
 }
 
-async fn handle(mut stream: TcpStream, addr: SocketAddr) {
+async fn handle(mut stream: TcpStream, addr: SocketAddr, config: EchoConfig) {
     let mut buf = Vec::with_capacity(1024);
     let mut connected = true;
+    let mut stats = ConnectionStats::new();
+    if config.chunk_size.is_some() {
+        if let Err(e) = stream.set_nodelay(true) {
+            warn!(client=%addr, error=%e, "couldn't disable Nagle's algorithm");
+        }
+    }
+    if let Some(ref banner) = config.banner {
+        match stream.write_all(banner).await {
+            Ok(()) => {
+                stats.wrote(banner.len());
+                debug!(client=%addr, bytes=banner.len(), "sent banner");
+            }
+            Err(e) => {
+                warn!(client=%addr, error=%e, "write banner failed");
+                connected = false;
+            }
+        }
+    }
     while connected {
         match stream.read_buf(&mut buf).await {
             Ok(n) => {
+                stats.read(n);
                 debug!(client=%addr, bytes=n, data=%String::from_utf8_lossy(&buf), "read ok");
                 if n == 0 { connected = false; }
 
-                match stream.write_all(&buf).await {
+                let echo = (config.transform)(&buf);
+                if !config.response_delay.is_zero() {
+                    sleep(config.response_delay).await;
+                }
+                let chunk_size = config.chunk_size.map_or(echo.len().max(1), NonZeroUsize::get);
+                let mut written = Ok(());
+                for chunk in echo.chunks(chunk_size) {
+                    written = stream.write_all(chunk).await;
+                    if written.is_err() {
+                        break;
+                    }
+                }
+                match written {
                     Ok(()) => {
-                        debug!(client=%addr, bytes=buf.len(), "write ok");
+                        stats.wrote(echo.len());
+                        debug!(client=%addr, bytes=echo.len(), "write ok");
                         buf.clear();
 
                     }
@@ -46,5 +120,121 @@ async fn handle(mut stream: TcpStream, addr: SocketAddr) {
             }
         }
     }
-    info!(client=%addr, "disconnect");
+    info!(client=%addr, duration=?stats.duration(), bytes_read=stats.bytes_read, bytes_written=stats.bytes_written, "disconnect");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    /// Log lines written on this thread, which is every task's in a single-threaded test
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Logs {
+        fn capture(&self) -> tracing::subscriber::DefaultGuard {
+            let logs = self.clone();
+            let subscriber = tracing_subscriber::fmt().with_ansi(false).with_max_level(tracing::Level::DEBUG).with_writer(move || logs.clone()).finish();
+            tracing::subscriber::set_default(subscriber)
+        }
+
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).lines().map(str::to_string).collect()
+        }
+
+        /// The first line containing `needle`, giving tasks a moment to log it
+        async fn wait_for(&self, needle: &str) -> String {
+            for _ in 0..500 {
+                if let Some(line) = self.lines().into_iter().find(|l| l.contains(needle)) {
+                    return line;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("never logged {needle:?}: {:#?}", self.lines());
+        }
+    }
+
+    /// The value logged for `name` in a log line
+    fn field(line: &str, name: &str) -> String {
+        line.split(&format!(" {name}=")).nth(1).unwrap().split(' ').next().unwrap().to_string()
+    }
+
+    async fn start(config: EchoConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(Listener::from(listener), config));
+        addr
+    }
+
+    /// Send `data`, close our side, and read everything that comes back
+    async fn echo(addr: SocketAddr, data: &[u8]) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(data).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed).await.unwrap();
+        echoed
+    }
+
+    #[tokio::test]
+    async fn disconnect_is_logged_with_stats() {
+        let logs = Logs::default();
+        let _guard = logs.capture();
+        let addr = start(EchoConfig::default()).await;
+        // Only returns once the server has closed the connection
+        assert_eq!(echo(addr, b"hello, world").await, b"hello, world");
+
+        let line = logs.wait_for(" disconnect ").await;
+        assert_eq!(field(&line, "bytes_read"), "12");
+        assert_eq!(field(&line, "bytes_written"), "12");
+        assert_ne!(field(&line, "duration"), "0ns");
+    }
+
+    #[tokio::test]
+    async fn transformed_echo() {
+        let addr = start(EchoConfig { transform: uppercase, ..EchoConfig::default() }).await;
+        assert_eq!(echo(addr, b"Hello, World!").await, b"HELLO, WORLD!");
+    }
+
+    #[tokio::test]
+    async fn banner_comes_first() {
+        let addr = start(EchoConfig { banner: Some(b"welcome\n".to_vec()), ..EchoConfig::default() }).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut banner = [0u8; 8];
+        stream.read_exact(&mut banner).await.unwrap();
+        assert_eq!(&banner, b"welcome\n");
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delayed_echo() {
+        let delay = Duration::from_secs(2);
+        let addr = start(EchoConfig { response_delay: delay, ..EchoConfig::default() }).await;
+        let started = tokio::time::Instant::now();
+        assert_eq!(echo(addr, b"slowly").await, b"slowly");
+        assert!(started.elapsed() >= delay);
+    }
+
+    #[tokio::test]
+    async fn chunked_echo_arrives_intact() {
+        let addr = start(EchoConfig { chunk_size: NonZeroUsize::new(3), ..EchoConfig::default() }).await;
+        let payload: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+        assert_eq!(echo(addr, &payload).await, payload);
+    }
 }
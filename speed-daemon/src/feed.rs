@@ -0,0 +1,93 @@
+//! A live feed of issued tickets as JSON, served as server-sent events for dashboards
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error, info, warn};
+use common::send;
+use crate::{Ticket, TicketCallback};
+
+/// Tickets a slow subscriber can fall behind by before it starts missing them
+const FEED_CAPACITY: usize = 1024;
+
+impl Ticket {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "plate": String::from_utf8_lossy(&self.plate),
+            "road": self.road,
+            "mile1": self.mile1,
+            "timestamp1": self.timestamp1,
+            "mile2": self.mile2,
+            "timestamp2": self.timestamp2,
+            "speed": self.speed,
+            "mph": self.speed_mph(),
+        })
+    }
+}
+
+/// Start serving the feed on `listener`, returning a callback that publishes each ticket to it.
+/// `inner` is still called for every ticket.
+pub(crate) fn start(listener: TcpListener, inner: Option<TicketCallback>) -> TicketCallback {
+    if let Ok(address) = listener.local_addr() {
+        info!(address=%address, "serving ticket feed");
+    }
+    let (tickets, _) = broadcast::channel(FEED_CAPACITY);
+    tokio::spawn(accept_subscribers(listener, tickets.clone()));
+    TicketCallback(Arc::new(move |ticket: &Ticket| {
+        if let Some(TicketCallback(ref inner)) = inner {
+            inner(ticket);
+        }
+        // An error just means nobody is subscribed right now
+        let _ = tickets.send(ticket.to_json().to_string());
+    }))
+}
+
+async fn accept_subscribers(listener: TcpListener, tickets: broadcast::Sender<String>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                info!(subscriber=%addr, "feed subscriber connected");
+                tokio::spawn(subscribe(stream, addr, tickets.subscribe()));
+            }
+            Err(e) => {
+                error!(error=?e, "feed accept failed");
+            }
+        }
+    }
+}
+
+/// Answer any HTTP request with an event stream, one event per ticket
+async fn subscribe(stream: TcpStream, addr: SocketAddr, mut tickets: broadcast::Receiver<String>) {
+    let mut stream = BufReader::new(stream);
+    // We don't care what was asked for, but let the client finish asking before we answer
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match stream.read_line(&mut line).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) if line.trim_end().is_empty() => break,
+            Ok(_) => debug!(subscriber=%addr, line=line.trim_end(), "feed request"),
+        }
+    }
+    let header = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n";
+    if send(stream.get_mut(), header.as_bytes()).await.is_err() {
+        return;
+    }
+    loop {
+        match tickets.recv().await {
+            Ok(ticket) => {
+                if send(stream.get_mut(), format!("data: {ticket}\n\n").as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                warn!(subscriber=%addr, missed=missed, "feed subscriber fell behind");
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+    info!(subscriber=%addr, "feed subscriber disconnected");
+}
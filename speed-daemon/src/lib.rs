@@ -1,10 +1,14 @@
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
 use std::io;
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use common::{flush, send, tick, ConnectionStats, Listener};
 use nom::branch::alt;
 use nom::bytes::streaming::tag;
 use nom::IResult;
@@ -12,11 +16,109 @@ use nom::combinator::map;
 use nom::multi::length_count;
 use nom::number::streaming::{be_u16, be_u32, be_u8};
 use nom::sequence::tuple;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, BufWriter};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::select;
-use tokio::time::interval;
-use tracing::{debug, error, info};
+use tokio::time::{interval, Interval};
+use tracing::{debug, error, info, warn};
+
+mod feed;
+mod stress;
+pub use stress::{stress, StressReport};
+
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    /// How far over the limit a car may go before being ticketed, in hundredths of a mph.
+    /// Speeds are compared with integer math, so this is exact: the default of 10 tickets
+    /// anything strictly faster than limit + 0.1 mph.
+    pub speed_tolerance: u32,
+    /// How a car's speed is compared to the limit
+    pub limit_comparison: LimitComparison,
+    /// Let a camera send another `IAmCamera` for the same road to move to a new mile, for
+    /// test harnesses simulating moving cameras. Normally a second identity is an error.
+    pub allow_camera_moves: bool,
+    /// Stricter limits for particular roads; the lower of this and the camera's limit applies
+    pub speed_limit_overrides: HashMap<u16, u16>,
+    /// Reject dispatchers that claim more roads than this
+    pub max_dispatcher_roads: usize,
+    /// Called with each ticket as it's issued, e.g. to record it somewhere other than the logs
+    pub on_ticket: Option<TicketCallback>,
+    /// Ignore plate reports timestamped outside this range, as a camera with a broken clock
+    /// would otherwise produce nonsense tickets
+    pub timestamp_range: Option<RangeInclusive<u32>>,
+    /// Share tickets for a road between all its dispatchers in turn, rather than giving them to
+    /// whichever dispatcher checks first
+    pub round_robin_dispatch: bool,
+    /// Accept `WantHeartbeat` from a client that hasn't yet said whether it's a camera or a
+    /// dispatcher, as the spec allows. Plate reports are always rejected until a client has
+    /// identified as a camera.
+    pub heartbeat_before_identify: bool,
+    /// Keep at most this many observations of a plate on a road, forgetting the oldest beyond
+    /// that, so a camera repeating one plate can't grow its history without bound
+    pub max_observations_per_plate_road: usize,
+    /// Reject heartbeat requests for periods outside this range. A request for no heartbeat at all
+    /// is always fine.
+    pub heartbeat_range: Option<RangeInclusive<Duration>>,
+    /// Keep at most this many tickets waiting for a dispatcher, e.g. for roads no dispatcher
+    /// ever covers
+    pub max_pending_tickets: Option<NonZeroUsize>,
+    /// What to do with a new ticket when `max_pending_tickets` are already waiting
+    pub pending_ticket_policy: PendingTicketPolicy,
+    /// Require clients to send an `Authenticate` message (0x90, then the secret as a string)
+    /// carrying this before they identify as a camera or dispatcher
+    pub auth_secret: Option<Vec<u8>>,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            speed_tolerance: 10,
+            limit_comparison: LimitComparison::OverTolerance,
+            allow_camera_moves: false,
+            speed_limit_overrides: HashMap::new(),
+            max_dispatcher_roads: 255,
+            on_ticket: None,
+            timestamp_range: None,
+            round_robin_dispatch: false,
+            heartbeat_before_identify: true,
+            max_observations_per_plate_road: 10_000,
+            heartbeat_range: None,
+            max_pending_tickets: None,
+            pending_ticket_policy: PendingTicketPolicy::DropOldest,
+            auth_secret: None,
+        }
+    }
+}
+
+/// When a car's speed counts as over the limit
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LimitComparison {
+    /// Faster than the limit plus `speed_tolerance`
+    OverTolerance,
+    /// Faster than the limit at all
+    Over,
+    /// At the limit or faster
+    AtOrOver,
+}
+
+/// How to stay within `max_pending_tickets`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PendingTicketPolicy {
+    /// Throw away the longest-waiting ticket to make room
+    DropOldest,
+    /// Don't issue the new ticket, leaving its days free to be ticketed later
+    StopIssuing,
+}
+
+/// A hook run for every issued ticket. It's called with the database locked, so it should be quick.
+#[derive(Clone)]
+pub struct TicketCallback(pub Arc<dyn Fn(&Ticket) + Send + Sync>);
+
+impl Debug for TicketCallback {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TicketCallback")
+    }
+}
 
 #[derive(Debug, Eq, PartialEq)]
 struct Camera {
@@ -27,7 +129,7 @@ struct Camera {
 
 #[derive(Debug, Eq, PartialEq)]
 struct Dispatcher {
-    roads: Vec<u16>,
+    roads: HashSet<u16>,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -47,20 +149,78 @@ struct ErrorMsg {
 }
 
 impl ErrorMsg {
+    /// Messages are sent as a protocol str, so anything past 255 bytes is cut off here
     fn msg(s: &str) -> ErrorMsg {
-        ErrorMsg { message: s.to_string() }
+        let mut end = s.len().min(MAX_STR_LEN);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        ErrorMsg { message: s[..end].to_string() }
+    }
+}
+
+/// Protocol violations that are reported to the client before disconnecting
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum ProtocolError {
+    AlreadyHeartbeat,
+    AlreadyIdentified,
+    WrongClientType,
+    InvalidInput,
+    TooManyRoads,
+    NotIdentified,
+    HeartbeatOutOfRange,
+    NotAuthenticated,
+    BadSecret,
+}
+
+impl ProtocolError {
+    fn message(&self) -> &'static str {
+        match self {
+            ProtocolError::AlreadyHeartbeat => "already requested heartbeat",
+            ProtocolError::AlreadyIdentified => "already sent client type",
+            ProtocolError::WrongClientType => "wrong client type",
+            ProtocolError::InvalidInput => "invalid input",
+            ProtocolError::TooManyRoads => "too many roads",
+            ProtocolError::NotIdentified => "identify as a camera or dispatcher first",
+            ProtocolError::HeartbeatOutOfRange => "heartbeat interval out of range",
+            ProtocolError::NotAuthenticated => "authenticate first",
+            ProtocolError::BadSecret => "bad secret",
+        }
     }
 }
 
 #[derive(Debug)]
-struct Ticket {
-    plate: Vec<u8>,
-    road: u16,
-    mile1: u16,
-    timestamp1: u32,
-    mile2: u16,
-    timestamp2: u32,
-    speed: u16, // (100x miles per hour)
+pub struct Ticket {
+    pub plate: Vec<u8>,
+    pub road: u16,
+    pub mile1: u16,
+    pub timestamp1: u32,
+    pub mile2: u16,
+    pub timestamp2: u32,
+    pub speed: u16, // (100x miles per hour)
+}
+
+impl Ticket {
+    /// The ticketed speed in miles per hour
+    pub fn speed_mph(&self) -> f64 {
+        self.speed as f64 / 100.0
+    }
+
+    /// The ticketed speed in kilometres per hour
+    pub fn speed_kmh(&self) -> f64 {
+        self.speed_mph() * 1.609344
+    }
+}
+
+struct Authenticate {
+    secret: Vec<u8>,
+}
+
+impl Debug for Authenticate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // Every packet is logged, and this one shouldn't be
+        f.write_str("Authenticate")
+    }
 }
 
 #[derive(Debug)]
@@ -76,11 +236,16 @@ impl ToMsg for Heartbeat {
     }
 }
 
+/// Protocol strings have a single length byte
+const MAX_STR_LEN: usize = u8::MAX as usize;
+
 impl ToMsg for &[u8] {
     fn to_msg(&self) -> Vec<u8> {
-        let mut v = Vec::with_capacity(self.len() + 1);
-        v.push(self.len() as u8);
-        v.extend_from_slice(self);
+        // Never let the length byte disagree with the bytes that follow it
+        let s = &self[..self.len().min(MAX_STR_LEN)];
+        let mut v = Vec::with_capacity(s.len() + 1);
+        v.push(s.len() as u8);
+        v.extend_from_slice(s);
         v
     }
 }
@@ -94,6 +259,12 @@ impl ToMsg for ErrorMsg {
     }
 }
 
+impl ToMsg for ProtocolError {
+    fn to_msg(&self) -> Vec<u8> {
+        ErrorMsg::msg(self.message()).to_msg()
+    }
+}
+
 impl ToMsg for Ticket {
     fn to_msg(&self) -> Vec<u8> {
         let mut msg = Vec::new();
@@ -134,7 +305,6 @@ struct IAmDispatcher {
 
 #[derive(Debug, Eq, PartialEq)]
 struct Observation {
-    road: u16,
     mile: u16,
     timestamp: u32,
 }
@@ -153,65 +323,169 @@ impl PartialOrd for Observation {
 
 #[derive(Debug, Default)]
 struct Database {
+    config: DaemonConfig,
     speed_limits: HashMap<u16, u16>,
-    observations: HashMap<Vec<u8>, Vec<Observation>>,
+    /// Plate -> road -> observations of that plate on that road, sorted by timestamp
+    observations: HashMap<Vec<u8>, HashMap<u16, Vec<Observation>>>,
     tickets_issued: HashMap<Vec<u8>, Vec<u32>>,
-    tickets_to_send: Vec<Ticket>,
+    /// Tickets waiting for a dispatcher, and which dispatcher's turn it is to send each one when
+    /// sharing them round-robin
+    tickets_to_send: Vec<(Ticket, Option<SocketAddr>)>,
+    /// Road -> connected dispatchers for it, oldest first, and the index of whose turn it is
+    dispatchers: HashMap<u16, (Vec<SocketAddr>, usize)>,
+    /// Road -> tickets issued on it since startup
+    tickets_by_road: HashMap<u16, u64>,
 }
 
 impl Database {
+    fn new(config: DaemonConfig) -> Database {
+        Database { config, ..Database::default() }
+    }
+
     fn record_speed_limit(&mut self, road: u16, limit: u16) {
         self.speed_limits.insert(road, limit);
     }
 
-    fn record_observation(&mut self, plate: Vec<u8>, road: u16, mile: u16, timestamp: u32) {
-        let obs = self.observations.entry(plate.clone()).or_default();
-        obs.push(Observation { road, mile, timestamp });
-        obs.sort_unstable();
-        if obs.len() > 1 {
-            self.issue_tickets(&plate, road);
+    /// Record a batch of reports from one camera, sorting each affected plate's history once
+    fn record_observations(&mut self, road: u16, mile: u16, reports: impl IntoIterator<Item = PlateReport>) {
+        let mut touched = HashSet::new();
+        for report in reports {
+            self.observations.entry(report.plate.clone()).or_default().entry(road).or_default()
+                .push(Observation { mile, timestamp: report.timestamp });
+            touched.insert(report.plate);
+        }
+        for plate in touched {
+            let obs = self.observations.get_mut(&plate).and_then(|roads| roads.get_mut(&road))
+                .expect("just recorded an observation");
+            obs.sort_unstable();
+            if obs.len() > 1 {
+                self.issue_tickets(&plate, road);
+            }
+            // Only trim once the new reports have had their chance at a ticket
+            let obs = self.observations.get_mut(&plate).and_then(|roads| roads.get_mut(&road))
+                .expect("just recorded an observation");
+            let excess = obs.len().saturating_sub(self.config.max_observations_per_plate_road.max(2));
+            if excess > 0 {
+                debug!(plate=%String::from_utf8_lossy(&plate), road=road, dropped=excess, "too many observations, forgetting the oldest");
+                obs.drain(..excess);
+            }
         }
     }
 
     fn issue_tickets(&mut self, plate: &[u8], road: u16) {
-        let &limit = self.speed_limits.get(&road).expect("no speed limit for observed road");
-        let obs: Vec<_> = self.observations.get(plate).map(|os| os.iter().filter(|o| o.road == road).collect()).unwrap_or_default();
+        let &camera_limit = self.speed_limits.get(&road).expect("no speed limit for observed road");
+        let limit = match self.config.speed_limit_overrides.get(&road) {
+            Some(&o) => o.min(camera_limit),
+            None => camera_limit,
+        };
+        let Some(obs) = self.observations.get(plate).and_then(|roads| roads.get(&road)) else { return };
         let p = String::from_utf8_lossy(plate);
 
         for w in obs.windows(2) {
             let [o1, o2] = w else { panic!("non-2-sized windows: {w:?}") };
-            let speed = o2.mile.abs_diff(o1.mile) as f64 / ((o2.timestamp - o1.timestamp) as f64 / 3600.0);
-            // debug!(plate=%p, road=road, o1=?o1, o2=?o2, speed=speed, limit=limit, "observed");
-            if speed > (limit as f64) + 0.1 {
+            let elapsed = (o2.timestamp - o1.timestamp) as u64;
+            if elapsed == 0 {
+                debug!(plate=%p, o1=?o1, o2=?o2, "simultaneous observations, can't compute speed");
+                continue;
+            }
+            let day1 = o1.timestamp / 86400;
+            let day2 = o2.timestamp / 86400;
+            // A car gets one ticket a day, so once either day has one there's nothing to work out.
+            // This makes replayed and late reports for days already ticketed cheap.
+            if self.tickets_issued.get(plate).is_some_and(|issued| issued.contains(&day1) || issued.contains(&day2)) {
+                debug!(plate=%p, day1=day1, day2=day2, "already issued ticket on this day");
+                continue;
+            }
+            // Everything in hundredths of a mph, scaled by elapsed seconds, so there's no rounding
+            let distance = o2.mile.abs_diff(o1.mile) as u64 * 3600 * 100;
+            let at_limit = limit as u64 * 100 * elapsed;
+            let speeding = match self.config.limit_comparison {
+                LimitComparison::OverTolerance => distance > at_limit + self.config.speed_tolerance as u64 * elapsed,
+                LimitComparison::Over => distance > at_limit,
+                LimitComparison::AtOrOver => distance >= at_limit,
+            };
+            if speeding {
+                if self.config.max_pending_tickets.is_some_and(|max| self.tickets_to_send.len() >= max.get()) {
+                    match self.config.pending_ticket_policy {
+                        PendingTicketPolicy::DropOldest => {
+                            let (dropped, _) = self.tickets_to_send.remove(0);
+                            warn!(ticket=?dropped, "too many tickets waiting for dispatchers, dropping the oldest");
+                        }
+                        PendingTicketPolicy::StopIssuing => {
+                            warn!(plate=%p, road=road, day1=day1, day2=day2, "too many tickets waiting for dispatchers, not issuing");
+                            continue;
+                        }
+                    }
+                }
                 // Issue a ticket
-                let day1 = o1.timestamp / 86400;
-                let day2 = o2.timestamp / 86400;
                 let issued = self.tickets_issued.entry(plate.to_owned()).or_default();
-                if issued.contains(&day1) || (day1 != day2 && issued.contains(&day2)) {
-                    debug!(plate=%p, day1=day1, day2=day2, "already issued ticket on this day");
-                } else {
-                    if !issued.contains(&day1) { issued.push(day1) };
-                    if day1 != day2 && !issued.contains(&day2) { issued.push(day2) };
-                    let ticket = Ticket {
-                        plate: plate.to_owned(),
-                        road,
-                        mile1: o1.mile,
-                        timestamp1: o1.timestamp,
-                        mile2: o2.mile,
-                        timestamp2: o2.timestamp,
-                        speed: (speed * 100.0).round() as u16,
-                    };
-                    info!(plate=%p, ticket=?ticket, "issuing ticket");
-                    self.tickets_to_send.push(ticket);
+                issued.push(day1);
+                if day1 != day2 { issued.push(day2) };
+                let ticket = Ticket {
+                    plate: plate.to_owned(),
+                    road,
+                    mile1: o1.mile,
+                    timestamp1: o1.timestamp,
+                    mile2: o2.mile,
+                    timestamp2: o2.timestamp,
+                    speed: ((distance + elapsed / 2) / elapsed).min(u16::MAX as u64) as u16,
+                };
+                info!(plate=%p, ticket=?ticket, mph=ticket.speed_mph(), kmh=ticket.speed_kmh(), "issuing ticket");
+                if let Some(TicketCallback(ref on_ticket)) = self.config.on_ticket {
+                    on_ticket(&ticket);
                 }
+                self.tickets_to_send.push((ticket, None));
+                *self.tickets_by_road.entry(road).or_default() += 1;
+            }
+        }
+    }
+
+    fn add_dispatcher(&mut self, addr: SocketAddr, roads: &HashSet<u16>) {
+        for &road in roads {
+            self.dispatchers.entry(road).or_default().0.push(addr);
+        }
+    }
+
+    fn remove_dispatcher(&mut self, addr: SocketAddr) {
+        self.dispatchers.retain(|_road, (dispatchers, _next)| {
+            dispatchers.retain(|&d| d != addr);
+            !dispatchers.is_empty()
+        });
+    }
+
+    /// Give each waiting ticket to the next dispatcher for its road in turn, unless it's already
+    /// been given to one that's still connected
+    fn assign_tickets(&mut self) {
+        for (ticket, assigned) in self.tickets_to_send.iter_mut() {
+            let Some((dispatchers, next)) = self.dispatchers.get_mut(&ticket.road) else { continue };
+            if assigned.is_some_and(|a| dispatchers.contains(&a)) {
+                continue;
             }
+            *assigned = Some(dispatchers[*next % dispatchers.len()]);
+            *next = next.wrapping_add(1);
         }
     }
 
-    fn get_ticket_to_send(&mut self, roads: &[u16]) -> Option<Ticket> {
+    fn get_ticket_to_send(&mut self, addr: SocketAddr, roads: &HashSet<u16>) -> Option<Ticket> {
+        let round_robin = self.config.round_robin_dispatch;
+        if round_robin {
+            self.assign_tickets();
+        }
         self.tickets_to_send.iter()
-            .position(|t| roads.contains(&t.road))
-            .map(|p| self.tickets_to_send.remove(p))
+            .position(|(t, assigned)| roads.contains(&t.road) && (!round_robin || *assigned == Some(addr)))
+            .map(|p| self.tickets_to_send.remove(p).0)
+    }
+
+    /// Put back a ticket that couldn't be delivered so another dispatcher can pick it up
+    fn requeue_ticket(&mut self, ticket: Ticket) {
+        self.tickets_to_send.insert(0, (ticket, None));
+    }
+
+    fn observation_rows(&self) -> Vec<ObservationRow> {
+        self.observations.iter()
+            .flat_map(|(plate, roads)| roads.iter()
+                .flat_map(move |(&road, obs)| obs.iter().map(move |o| (plate.clone(), road, o.timestamp, o.mile))))
+            .collect()
     }
 }
 
@@ -231,6 +505,14 @@ fn parse_plate(input: &[u8]) -> IResult<&[u8], PlateReport> {
         .map(|(rest, (_, plate, timestamp))| (rest, PlateReport { plate, timestamp }))
 }
 
+fn parse_authenticate(input: &[u8]) -> IResult<&[u8], Authenticate> {
+    tuple((
+        tag(b"\x90"),
+        parse_str
+    ))(input)
+        .map(|(rest, (_, secret))| (rest, Authenticate { secret }))
+}
+
 fn parse_wantheartbeat(input: &[u8]) -> IResult<&[u8], WantHeartbeat> {
     tuple((
         tag(b"\x40"),
@@ -263,28 +545,95 @@ enum IncomingPacket {
     IAmCamera(IAmCamera),
     IAmDispatcher(IAmDispatcher),
     PlateReport(PlateReport),
+    Authenticate(Authenticate),
 }
 
 fn parse_incoming(input: &[u8]) -> IResult<&[u8], IncomingPacket> {
     alt((
-        map(parse_plate, |r| IncomingPacket::PlateReport(r)),
-        map(parse_wantheartbeat, |r| IncomingPacket::WantHeartbeat(r)),
-        map(parse_iamcamera, |r| IncomingPacket::IAmCamera(r)),
-        map(parse_iamdispatcher, |r| IncomingPacket::IAmDispatcher(r)),
+        map(parse_plate, IncomingPacket::PlateReport),
+        map(parse_wantheartbeat, IncomingPacket::WantHeartbeat),
+        map(parse_iamcamera, IncomingPacket::IAmCamera),
+        map(parse_iamdispatcher, IncomingPacket::IAmDispatcher),
+        map(parse_authenticate, IncomingPacket::Authenticate),
     ))(input)
 }
 
-pub async fn serve(address: SocketAddr) -> io::Result<()> {
-    info!("starting");
-    let listener = TcpListener::bind(address).await?;
+/// A server's shared state. Clones share it, so one can be kept to read figures from or export
+/// observations while `serve` runs with another.
+#[derive(Debug, Clone)]
+pub struct Daemon {
+    config: DaemonConfig,
+    database: Arc<Mutex<Database>>,
+}
+
+impl Daemon {
+    /// A server with nothing recorded yet. If there's a `ticket_feed`, issued tickets are
+    /// streamed as JSON server-sent events to anyone who connects to it; serving that spawns a
+    /// task, so it has to happen inside a tokio runtime.
+    pub fn new(mut config: DaemonConfig, ticket_feed: Option<TcpListener>) -> Daemon {
+        if let Some(feed) = ticket_feed {
+            config.on_ticket = Some(feed::start(feed, config.on_ticket.take()));
+        }
+        let database = Arc::new(Mutex::new(Database::new(config.clone())));
+        Daemon { config, database }
+    }
+
+    /// Ticket figures since the server started
+    pub fn metrics(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            tickets_by_road: self.database.lock().unwrap().tickets_by_road.clone(),
+        }
+    }
+
+    /// Write every observation the server has recorded to `path` as CSV, with the columns plate,
+    /// road, mile and timestamp, returning how many were written. The database is only locked
+    /// long enough to copy them.
+    pub async fn export_observations(&self, path: &Path) -> io::Result<usize> {
+        let rows = self.database.lock().unwrap().observation_rows();
+        let count = rows.len();
+        tokio::fs::write(path, observations_csv(rows)).await?;
+        info!(path=?path, observations=count, "exported observations");
+        Ok(count)
+    }
+}
+
+/// Ticket figures for a server, from `Daemon::metrics`
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Road -> tickets issued on it since startup
+    pub tickets_by_road: HashMap<u16, u64>,
+}
 
-    let database = Arc::new(Mutex::new(Database::default()));
+/// Plate, road, timestamp and mile of each observation, in that order so they sort sensibly
+type ObservationRow = (Vec<u8>, u16, u32, u16);
+
+fn observations_csv(mut rows: Vec<ObservationRow>) -> String {
+    rows.sort_unstable();
+    let mut csv = String::from("plate,road,mile,timestamp\n");
+    for (plate, road, timestamp, mile) in &rows {
+        let plate = String::from_utf8_lossy(plate);
+        let plate = if plate.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", plate.replace('"', "\"\""))
+        } else {
+            plate.into_owned()
+        };
+        csv.push_str(&format!("{},{},{},{}\n", plate, road, mile, timestamp));
+    }
+    csv
+}
+
+/// Serve cameras and dispatchers on `listener`
+pub async fn serve(listener: Listener, daemon: Daemon) -> io::Result<()> {
+    info!("starting");
+    let Daemon { config, database } = daemon;
 
     loop {
         match listener.accept().await {
-            Ok((stream, addr)) => {
-                info!(client=%addr, "connection received");
-                tokio::spawn(handle(stream, addr, database.clone()));
+            Ok((stream, addr, permit)) => {
+                if listener.log_accept() {
+                    info!(client=%addr, "connection received");
+                }
+                tokio::spawn(permit.hold(handle(stream, addr, database.clone(), config.clone())));
             }
             Err(e) => {
                 error!(error=?e, "accept failed");
@@ -293,55 +642,98 @@ pub async fn serve(address: SocketAddr) -> io::Result<()> {
     }
 }
 
-async fn handle(mut stream: TcpStream, addr: SocketAddr, database: Arc<Mutex<Database>>) {
-    let mut heartbeat = interval(Duration::from_secs(86400*365));
-    // First tick happens right away
-    heartbeat.tick().await;
-    let mut requested_heartbeat = false;
+/// Most plate reports a camera queues before they're applied to the database
+const OBSERVATION_BATCH: usize = 256;
+
+/// Apply a camera's queued plate reports under a single lock acquisition
+fn record_pending(database: &Mutex<Database>, client_type: &ClientType, pending: &mut Vec<PlateReport>) {
+    if pending.is_empty() {
+        return;
+    }
+    if let ClientType::Camera(c) = client_type {
+        debug!(reports=pending.len(), road=c.road, mile=c.mile, "recording observations");
+        database.lock().unwrap().record_observations(c.road, c.mile, pending.drain(..));
+    }
+}
+
+/// Report a protocol violation to the client. The connection is closed afterwards either way,
+/// so a failed write has nothing left to do beyond the log line in `send`.
+async fn send_error(stream: &mut BufWriter<TcpStream>, stats: &mut ConnectionStats, error: ProtocolError) {
+    let msg = error.to_msg();
+    if send(stream, &msg).await.is_ok() && flush(stream).await.is_ok() {
+        stats.wrote(msg.len());
+    }
+}
+
+async fn handle(stream: TcpStream, addr: SocketAddr, database: Arc<Mutex<Database>>, config: DaemonConfig) {
+    let mut stats = ConnectionStats::new();
+    handle_client(stream, addr, database.clone(), config, &mut stats).await;
+    database.lock().unwrap().remove_dispatcher(addr);
+    info!(client=%addr, duration=?stats.duration(), bytes_read=stats.bytes_read, bytes_written=stats.bytes_written, "disconnect");
+}
 
-    let mut dispatch_interval = interval(Duration::from_secs(86400*365));
-    // First tick happens right away
-    dispatch_interval.tick().await;
+async fn handle_client(stream: TcpStream, addr: SocketAddr, database: Arc<Mutex<Database>>, config: DaemonConfig, stats: &mut ConnectionStats) {
+    // Writes are buffered so a batch of tickets goes out together; flush after every batch
+    let mut stream = BufWriter::new(stream);
+    let mut heartbeat: Option<Interval> = None;
+    let mut requested_heartbeat = false;
+    let mut authenticated = config.auth_secret.is_none();
+    let mut dispatch_interval: Option<Interval> = None;
 
     let mut client_type = ClientType::Unknown;
     let mut buf = Vec::with_capacity(1024);
+    let mut pending = Vec::new();
     loop {
         select! {
-            _ = heartbeat.tick() => {
-                if requested_heartbeat {
-                    debug!(addr=?addr, "sending heartbeat");
-                    if let Err(e) = stream.write_all(&(Heartbeat {}.to_msg())).await {
-                        error!(addr=?addr, err=?e, "Heartbeat failed");
-                        return;
-                    }
+            _ = tick(&mut heartbeat) => {
+                debug!(addr=?addr, "sending heartbeat");
+                let msg = Heartbeat {}.to_msg();
+                if send(&mut stream, &msg).await.is_err() || flush(&mut stream).await.is_err() {
+                    error!(addr=?addr, "Heartbeat failed");
+                    return;
                 }
+                stats.wrote(msg.len());
             }
 
-            _ = dispatch_interval.tick() => {
-                match client_type {
-                    ClientType::Dispatcher(ref d) => {
-                        debug!(addr=?addr, dispatcher=?d, "checking for tickets");
-                        while let Some(t) = {
-                            let mut db = database.lock().unwrap();
-                            let t = db.get_ticket_to_send(&d.roads);
-                            t
-                        } {
-                            info!(addr=?addr, ticket=?t, "dispatching ticket");
-                            if let Err(e) = stream.write_all(&t.to_msg()).await {
-                                error!(addr=?addr, err=?e, "write ticket failed");
-                                return;
-                            }
+            _ = tick(&mut dispatch_interval) => {
+                if let ClientType::Dispatcher(ref d) = client_type {
+                    debug!(addr=?addr, dispatcher=?d, "checking for tickets");
+                    let mut sent = Vec::new();
+                    let mut ok = true;
+                    while let Some(t) = {
+                        let mut db = database.lock().unwrap();
+                        db.get_ticket_to_send(addr, &d.roads)
+                    } {
+                        info!(addr=?addr, ticket=?t, mph=t.speed_mph(), kmh=t.speed_kmh(), "dispatching ticket");
+                        let msg = t.to_msg();
+                        ok = send(&mut stream, &msg).await.is_ok();
+                        sent.push(t);
+                        if !ok {
+                            break;
+                        }
+                        stats.wrote(msg.len());
+                    }
+                    if !sent.is_empty() && (!ok || flush(&mut stream).await.is_err()) {
+                        // We can't tell how much of the batch arrived, so requeue all of it
+                        error!(addr=?addr, tickets=sent.len(), "write tickets failed, requeueing");
+                        let mut db = database.lock().unwrap();
+                        for t in sent.into_iter().rev() {
+                            db.requeue_ticket(t);
                         }
+                        return;
                     }
-                    _ => {}
                 }
             }
 
             b = stream.read_buf(&mut buf) => {
                 match b {
-                    Ok(n) if n > 0 => { }
-                    _ => {
-                        error!(addr=?addr, recv=?b, "lost connection");
+                    Ok(0) => {
+                        info!(addr=?addr, unparsed=buf.len(), "client closed connection");
+                        return;
+                    }
+                    Ok(n) => { stats.read(n); }
+                    Err(e) => {
+                        error!(addr=?addr, error=?e, "lost connection");
                         return;
                     }
                 }
@@ -349,26 +741,73 @@ async fn handle(mut stream: TcpStream, addr: SocketAddr, database: Arc<Mutex<Dat
                 loop {
                     match parse_incoming(&buf) {
                         Ok((left, packet)) => {
-                            buf = left.to_vec();
+                            // Drain rather than reallocate, so the buffer keeps room to read a burst at once
+                            let consumed = buf.len() - left.len();
+                            buf.drain(..consumed);
                             info!(addr=?addr, packet=?packet, client=?client_type, "packet received");
+                            if !matches!(packet, IncomingPacket::PlateReport(_)) {
+                                // Anything else may change the camera, so apply its reports first
+                                record_pending(&database, &client_type, &mut pending);
+                            }
+                            if matches!(packet, IncomingPacket::IAmCamera(_) | IncomingPacket::IAmDispatcher(_)) && !authenticated {
+                                error!(addr=?addr, "identified before authenticating");
+                                send_error(&mut stream, stats, ProtocolError::NotAuthenticated).await;
+                                return;
+                            }
                             match packet {
+                                IncomingPacket::Authenticate(a) => {
+                                    let Some(ref secret) = config.auth_secret else {
+                                        // Without a secret this server speaks the plain protocol
+                                        error!(addr=?addr, "authentication isn't enabled");
+                                        send_error(&mut stream, stats, ProtocolError::InvalidInput).await;
+                                        return;
+                                    };
+                                    if a.secret != *secret {
+                                        error!(addr=?addr, "wrong secret");
+                                        send_error(&mut stream, stats, ProtocolError::BadSecret).await;
+                                        return;
+                                    }
+                                    info!(addr=?addr, "authenticated");
+                                    authenticated = true;
+                                }
                                 IncomingPacket::WantHeartbeat(h) => {
                                     if requested_heartbeat {
                                         error!(addr=?addr, "already requested heartbeat");
-                                        let _ = stream.write_all(&ErrorMsg::msg("already requested heartbeat").to_msg()).await;
+                                        send_error(&mut stream, stats, ProtocolError::AlreadyHeartbeat).await;
+                                        return;
+                                    }
+                                    if client_type == ClientType::Unknown && !config.heartbeat_before_identify {
+                                        error!(addr=?addr, "heartbeat requested before identifying");
+                                        send_error(&mut stream, stats, ProtocolError::NotIdentified).await;
+                                        return;
+                                    }
+                                    // Deciseconds, which would overflow a u32 of milliseconds
+                                    let period = Duration::from_millis(h.interval as u64 * 100);
+                                    if h.interval != 0 && config.heartbeat_range.as_ref().is_some_and(|r| !r.contains(&period)) {
+                                        error!(addr=?addr, period=?period, "heartbeat interval out of range");
+                                        send_error(&mut stream, stats, ProtocolError::HeartbeatOutOfRange).await;
                                         return;
                                     }
                                     info!(addr=?addr, interval=h.interval, "want heartbeat");
                                     requested_heartbeat = true;
                                     if h.interval != 0 {
-                                        heartbeat = interval(Duration::from_millis((h.interval * 100) as u64));
-                                        heartbeat.tick().await;
+                                        let mut i = interval(period);
+                                        // First tick happens right away
+                                        i.tick().await;
+                                        heartbeat = Some(i);
                                     }
                                 }
                                 IncomingPacket::IAmCamera(c) => {
+                                    if let ClientType::Camera(ref mut camera) = client_type {
+                                        if config.allow_camera_moves && camera.road == c.road {
+                                            info!(addr=?addr, road=c.road, from=camera.mile, to=c.mile, "camera moved");
+                                            camera.mile = c.mile;
+                                            continue;
+                                        }
+                                    }
                                     if client_type != ClientType::Unknown {
                                         error!(addr=?addr, "already sent client type");
-                                        let _ = stream.write_all(&ErrorMsg::msg("already sent client type").to_msg()).await;
+                                        send_error(&mut stream, stats, ProtocolError::AlreadyIdentified).await;
                                         return;
                                     }
                                     database.lock().unwrap().record_speed_limit(c.road, c.limit);
@@ -379,29 +818,53 @@ async fn handle(mut stream: TcpStream, addr: SocketAddr, database: Arc<Mutex<Dat
                                 IncomingPacket::IAmDispatcher(d) => {
                                     if client_type != ClientType::Unknown {
                                         error!(addr=?addr, "already sent client type");
-                                        let _ = stream.write_all(&ErrorMsg::msg("already sent client type").to_msg()).await;
+                                        send_error(&mut stream, stats, ProtocolError::AlreadyIdentified).await;
                                         return;
                                     }
-                                    client_type = ClientType::Dispatcher(
-                                        Dispatcher { roads: d.roads }
-                                    );
-                                    dispatch_interval = interval(Duration::from_secs(1));
+                                    if d.roads.len() > config.max_dispatcher_roads {
+                                        error!(addr=?addr, roads=d.roads.len(), max=config.max_dispatcher_roads, "too many roads");
+                                        send_error(&mut stream, stats, ProtocolError::TooManyRoads).await;
+                                        return;
+                                    }
+                                    let dispatcher = Dispatcher { roads: d.roads.into_iter().collect() };
+                                    database.lock().unwrap().add_dispatcher(addr, &dispatcher.roads);
+                                    client_type = ClientType::Dispatcher(dispatcher);
+                                    dispatch_interval = Some(interval(Duration::from_secs(1)));
                                 }
                                 IncomingPacket::PlateReport(p) => {
-                                    if let ClientType::Camera(ref c) = client_type {
-                                        database.lock().unwrap().record_observation(p.plate, c.road, c.mile, p.timestamp);
+                                    if let ClientType::Camera(_) = client_type {
+                                        if config.timestamp_range.as_ref().is_some_and(|r| !r.contains(&p.timestamp)) {
+                                            warn!(addr=?addr, report=?p, range=?config.timestamp_range, "implausible timestamp, ignoring report");
+                                            continue;
+                                        }
+                                        pending.push(p);
+                                        if pending.len() >= OBSERVATION_BATCH {
+                                            record_pending(&database, &client_type, &mut pending);
+                                        }
+                                    } else if let ClientType::Dispatcher(ref d) = client_type {
+                                        // Dispatchers never see plates, so this is a confused client rather than a
+                                        // camera that forgot to identify
+                                        let mut roads: Vec<u16> = d.roads.iter().copied().collect();
+                                        roads.sort_unstable();
+                                        error!(addr=?addr, roads=?roads, report=?p, "dispatcher sent a PlateReport, is it a buggy client?");
+                                        send_error(&mut stream, stats, ProtocolError::WrongClientType).await;
+                                        return;
                                     } else {
                                         error!(addr=?addr, client_type=?client_type, "unexpected PlateReport");
-                                        let _ = stream.write_all(&ErrorMsg::msg("wrong client type").to_msg()).await;
+                                        send_error(&mut stream, stats, ProtocolError::WrongClientType).await;
                                         return;
                                     }
                                 }
                             }
                         }
-                        Err(nom::Err::Incomplete(_)) => break,
+                        Err(nom::Err::Incomplete(_)) => {
+                            record_pending(&database, &client_type, &mut pending);
+                            break;
+                        }
                         Err(e) => {
+                            record_pending(&database, &client_type, &mut pending);
                             error!(addr=?addr, error=?e, "invalid input");
-                            let _ = stream.write_all(&ErrorMsg::msg("invalid input").to_msg()).await;
+                            send_error(&mut stream, stats, ProtocolError::InvalidInput).await;
                             return;
                         }
                     }
@@ -410,3 +873,750 @@ async fn handle(mut stream: TcpStream, addr: SocketAddr, database: Arc<Mutex<Dat
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::time::{timeout_at, Instant};
+
+    /// Every strict prefix of a message should ask for more input, not fail
+    fn assert_incomplete<T: Debug>(msg: &[u8], parse: impl Fn(&[u8]) -> IResult<&[u8], T>) {
+        for end in 0..msg.len() {
+            let result = parse(&msg[..end]);
+            assert!(matches!(result, Err(nom::Err::Incomplete(_))), "{:?} parsed as {:?}", &msg[..end], result);
+        }
+    }
+
+    #[test]
+    fn plate_round_trip() {
+        let msg = PlateReport { plate: b"UN1X".to_vec(), timestamp: 1000 }.to_msg();
+        let mut input = msg.clone();
+        input.push(0x40);
+        let (rest, report) = parse_plate(&input).unwrap();
+        assert_eq!(rest, [0x40]);
+        assert_eq!(report.plate, b"UN1X");
+        assert_eq!(report.timestamp, 1000);
+        assert_incomplete(&msg, parse_plate);
+    }
+
+    #[test]
+    fn wantheartbeat_round_trip() {
+        let msg = [0x40, 0x00, 0x00, 0x04, 0xdb];
+        let (rest, want) = parse_wantheartbeat(&msg).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(want.interval, 1243);
+        assert_incomplete(&msg, parse_wantheartbeat);
+    }
+
+    #[test]
+    fn iamcamera_round_trip() {
+        let msg = IAmCamera { road: 66, mile: 100, limit: 60 }.to_msg();
+        let (rest, camera) = parse_iamcamera(&msg).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!((camera.road, camera.mile, camera.limit), (66, 100, 60));
+        assert_incomplete(&msg, parse_iamcamera);
+    }
+
+    #[test]
+    fn iamdispatcher_round_trip() {
+        let msg = IAmDispatcher { roads: vec![66, 368, 5000] }.to_msg();
+        let (rest, dispatcher) = parse_iamdispatcher(&msg).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(dispatcher.roads, [66, 368, 5000]);
+        assert_incomplete(&msg, parse_iamdispatcher);
+    }
+
+    #[test]
+    fn authenticate_round_trip() {
+        let mut msg = vec![0x90];
+        msg.extend_from_slice(&b"hunter2".as_slice().to_msg());
+        let (rest, auth) = parse_authenticate(&msg).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(auth.secret, b"hunter2");
+        assert_incomplete(&msg, parse_authenticate);
+        assert!(matches!(parse_incoming(&msg), Ok((_, IncomingPacket::Authenticate(_)))));
+        assert_eq!(format!("{auth:?}"), "Authenticate");
+    }
+
+    #[test]
+    fn unknown_packet_is_an_error() {
+        assert!(matches!(parse_incoming(&[0x99, 0x00]), Err(nom::Err::Error(_))));
+    }
+
+    #[test]
+    fn protocol_error_bytes() {
+        assert_eq!(ProtocolError::InvalidInput.to_msg(), b"\x10\x0dinvalid input");
+        let all = [
+            ProtocolError::AlreadyHeartbeat,
+            ProtocolError::AlreadyIdentified,
+            ProtocolError::WrongClientType,
+            ProtocolError::InvalidInput,
+            ProtocolError::TooManyRoads,
+            ProtocolError::NotIdentified,
+            ProtocolError::HeartbeatOutOfRange,
+            ProtocolError::NotAuthenticated,
+            ProtocolError::BadSecret,
+        ];
+        for error in all {
+            let message = error.message().as_bytes();
+            let mut expected = vec![0x10, message.len() as u8];
+            expected.extend_from_slice(message);
+            assert_eq!(error.to_msg(), expected, "{error:?}");
+        }
+    }
+
+    /// A database that's just ticketed a car doing 61 mph on road 1, where the limit is 60
+    fn ticketed(config: DaemonConfig) -> Database {
+        let mut db = Database::new(config);
+        db.record_speed_limit(1, 60);
+        db.record_observations(1, 0, [PlateReport { plate: b"RE05BKG".to_vec(), timestamp: 0 }]);
+        db.record_observations(1, 61, [PlateReport { plate: b"RE05BKG".to_vec(), timestamp: 3600 }]);
+        assert_eq!(db.tickets_to_send.len(), 1);
+        db
+    }
+
+    fn dispatcher(db: &mut Database, port: u16) -> (SocketAddr, HashSet<u16>) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let roads = HashSet::from([1]);
+        db.add_dispatcher(addr, &roads);
+        (addr, roads)
+    }
+
+    #[test]
+    fn failed_ticket_goes_to_another_dispatcher() {
+        for round_robin_dispatch in [false, true] {
+            let mut db = ticketed(DaemonConfig { round_robin_dispatch, ..DaemonConfig::default() });
+            let (first, first_roads) = dispatcher(&mut db, 1);
+            let (second, second_roads) = dispatcher(&mut db, 2);
+
+            // The first dispatcher takes the ticket, fails to write it, and disconnects
+            let ticket = db.get_ticket_to_send(first, &first_roads).unwrap();
+            db.requeue_ticket(ticket);
+            db.remove_dispatcher(first);
+
+            let ticket = db.get_ticket_to_send(second, &second_roads).expect("ticket lost");
+            assert_eq!(ticket.plate, b"RE05BKG");
+            assert!(db.get_ticket_to_send(second, &second_roads).is_none());
+        }
+    }
+
+    /// The ticketed speed, if any, for a car covering `miles` in `seconds` where the limit is 60
+    fn ticket_for(limit_comparison: LimitComparison, miles: u16, seconds: u32) -> Option<u16> {
+        let mut db = Database::new(DaemonConfig { limit_comparison, ..DaemonConfig::default() });
+        db.record_speed_limit(1, 60);
+        db.record_observations(1, 0, [PlateReport { plate: b"UN1X".to_vec(), timestamp: 0 }]);
+        db.record_observations(1, miles, [PlateReport { plate: b"UN1X".to_vec(), timestamp: seconds }]);
+        db.tickets_to_send.first().map(|(t, _)| t.speed)
+    }
+
+    #[test]
+    fn speed_at_the_limit() {
+        // 60 mph exactly
+        assert_eq!(ticket_for(LimitComparison::OverTolerance, 1, 60), None);
+        assert_eq!(ticket_for(LimitComparison::Over, 1, 60), None);
+        assert_eq!(ticket_for(LimitComparison::AtOrOver, 1, 60), Some(6000));
+    }
+
+    #[test]
+    fn speed_just_over_the_limit() {
+        // 60.01 mph, over the limit but within the tolerance
+        assert_eq!(ticket_for(LimitComparison::OverTolerance, 6001, 360_000), None);
+        assert_eq!(ticket_for(LimitComparison::Over, 6001, 360_000), Some(6001));
+    }
+
+    #[test]
+    fn speed_at_the_tolerance() {
+        // 60.1 mph exactly, which is right at the default tolerance of 0.1 mph
+        assert_eq!(ticket_for(LimitComparison::OverTolerance, 601, 36_000), None);
+        // 60.11 mph
+        assert_eq!(ticket_for(LimitComparison::OverTolerance, 6011, 360_000), Some(6011));
+    }
+
+    #[tokio::test]
+    async fn heartbeat_cadence() {
+        // On the real clock, as a paused one can run ahead of heartbeats still in the socket
+        let addr = start(DaemonConfig::default()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        // Every decisecond
+        client.write_all(&[0x40, 0, 0, 0, 1]).await.unwrap();
+        let started = Instant::now();
+        let mut byte = [0u8];
+        for _ in 0..5 {
+            timeout_at(started + Duration::from_secs(5), client.read_exact(&mut byte)).await.expect("too slow").unwrap();
+            assert_eq!(byte, [0x41]);
+        }
+        // None straight away, then one a period apart
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(500) && elapsed < Duration::from_millis(1500), "{elapsed:?}");
+    }
+
+    async fn start(config: DaemonConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(Listener::from(listener), Daemon::new(config, None)));
+        addr
+    }
+
+    /// Connect to the server and send it `messages`
+    async fn client(addr: SocketAddr, messages: &[Vec<u8>]) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&messages.concat()).await.unwrap();
+        stream
+    }
+
+    fn report(plate: &[u8], timestamp: u32) -> Vec<u8> {
+        PlateReport { plate: plate.to_vec(), timestamp }.to_msg()
+    }
+
+    /// Read the next ticket, skipping any heartbeats before it
+    async fn read_ticket(stream: &mut TcpStream) -> Ticket {
+        let read = async {
+            let mut header = [0x41u8; 2];
+            while header[0] == 0x41 {
+                stream.read_exact(&mut header[..1]).await.unwrap();
+            }
+            assert_eq!(header[0], 0x21);
+            stream.read_exact(&mut header[1..]).await.unwrap();
+            let mut body = vec![0u8; header[1] as usize + 16];
+            stream.read_exact(&mut body).await.unwrap();
+            let (plate, rest) = body.split_at(header[1] as usize);
+            let u16_at = |i: usize| u16::from_be_bytes([rest[i], rest[i + 1]]);
+            let u32_at = |i: usize| u32::from_be_bytes([rest[i], rest[i + 1], rest[i + 2], rest[i + 3]]);
+            Ticket {
+                plate: plate.to_vec(),
+                road: u16_at(0),
+                mile1: u16_at(2),
+                timestamp1: u32_at(4),
+                mile2: u16_at(8),
+                timestamp2: u32_at(10),
+                speed: u16_at(14),
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), read).await.expect("no ticket")
+    }
+
+    /// Read an error message, and then the end of the connection
+    async fn read_error(stream: &mut TcpStream) -> String {
+        let mut msg = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut msg)).await.expect("not disconnected").unwrap();
+        assert_eq!(msg[0], 0x10, "{msg:?}");
+        assert_eq!(msg[1] as usize, msg.len() - 2, "{msg:?}");
+        String::from_utf8(msg[2..].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn camera_moves_when_allowed() {
+        let addr = start(DaemonConfig { allow_camera_moves: true, ..DaemonConfig::default() }).await;
+        let _camera = client(addr, &[
+            IAmCamera { road: 1, mile: 0, limit: 60 }.to_msg(),
+            report(b"UN1X", 0),
+            IAmCamera { road: 1, mile: 61, limit: 60 }.to_msg(),
+            report(b"UN1X", 3600),
+        ]).await;
+        let mut dispatcher = client(addr, &[IAmDispatcher { roads: vec![1] }.to_msg()]).await;
+        let ticket = read_ticket(&mut dispatcher).await;
+        assert_eq!((ticket.mile1, ticket.mile2, ticket.speed), (0, 61, 6100));
+    }
+
+    #[tokio::test]
+    async fn camera_moves_are_an_error_by_default() {
+        let addr = start(DaemonConfig::default()).await;
+        let mut camera = client(addr, &[
+            IAmCamera { road: 1, mile: 0, limit: 60 }.to_msg(),
+            IAmCamera { road: 1, mile: 61, limit: 60 }.to_msg(),
+        ]).await;
+        assert_eq!(read_error(&mut camera).await, "already sent client type");
+    }
+
+    #[test]
+    fn speed_limit_override() {
+        // Drive `miles` in an hour on a 60 mph road
+        let drive = |miles, speed_limit_overrides| {
+            let mut db = Database::new(DaemonConfig { speed_limit_overrides, ..DaemonConfig::default() });
+            db.record_speed_limit(1, 60);
+            db.record_observations(1, 0, [PlateReport { plate: b"UN1X".to_vec(), timestamp: 0 }]);
+            db.record_observations(1, miles, [PlateReport { plate: b"UN1X".to_vec(), timestamp: 3600 }]);
+            db.tickets_to_send.len()
+        };
+        assert_eq!(drive(55, HashMap::new()), 0);
+        assert_eq!(drive(55, HashMap::from([(1, 50)])), 1);
+        // An override can only make a road stricter
+        assert_eq!(drive(65, HashMap::from([(1, 70)])), 1);
+        assert_eq!(drive(55, HashMap::from([(2, 50)])), 0);
+    }
+
+    #[test]
+    fn ticket_speed_units() {
+        let ticket = Ticket { plate: b"UN1X".to_vec(), road: 1, mile1: 0, timestamp1: 0, mile2: 1, timestamp2: 60, speed: 6000 };
+        assert_eq!(ticket.speed_mph(), 60.0);
+        assert!((ticket.speed_kmh() - 96.56064).abs() < 1e-9, "{}", ticket.speed_kmh());
+        // The wire format still carries hundredths of a mph
+        assert_eq!(ticket.to_msg()[ticket.to_msg().len() - 2..], 6000u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn dispatcher_road_limit() {
+        let addr = start(DaemonConfig { max_dispatcher_roads: 10, ..DaemonConfig::default() }).await;
+        let mut dispatcher = client(addr, &[IAmDispatcher { roads: (0..11).collect() }.to_msg()]).await;
+        assert_eq!(read_error(&mut dispatcher).await, "too many roads");
+
+        let addr = start(DaemonConfig::default()).await;
+        let _first = client(addr, &[IAmCamera { road: 254, mile: 0, limit: 60 }.to_msg(), report(b"UN1X", 0)]).await;
+        let _second = client(addr, &[IAmCamera { road: 254, mile: 1, limit: 60 }.to_msg(), report(b"UN1X", 30)]).await;
+        // Every road a u8 count allows, with the ticket's road last
+        let mut dispatcher = client(addr, &[IAmDispatcher { roads: (0..255).collect() }.to_msg()]).await;
+        assert_eq!(read_ticket(&mut dispatcher).await.road, 254);
+    }
+
+    #[test]
+    fn tickets_are_per_road() {
+        let mut db = Database::new(DaemonConfig::default());
+        db.record_speed_limit(1, 60);
+        db.record_speed_limit(2, 60);
+        // 100 miles in an hour, but the two sightings are on different roads
+        db.record_observations(1, 0, [PlateReport { plate: b"UN1X".to_vec(), timestamp: 0 }]);
+        db.record_observations(2, 100, [PlateReport { plate: b"UN1X".to_vec(), timestamp: 3600 }]);
+        assert!(db.tickets_to_send.is_empty());
+
+        db.record_observations(2, 0, [PlateReport { plate: b"UN1X".to_vec(), timestamp: 7200 }]);
+        let roads = &db.observations[b"UN1X".as_slice()];
+        assert_eq!(roads[&1].len(), 1);
+        assert_eq!(roads[&2].len(), 2);
+        assert_eq!(db.tickets_to_send.len(), 1);
+        let (ticket, _) = &db.tickets_to_send[0];
+        assert_eq!((ticket.road, ticket.mile1, ticket.mile2, ticket.speed), (2, 100, 0, 10000));
+    }
+
+    /// Log lines written on this thread, which is every task's in a single-threaded test
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Logs {
+        fn capture(&self) -> tracing::subscriber::DefaultGuard {
+            let logs = self.clone();
+            let subscriber = tracing_subscriber::fmt().with_ansi(false).with_max_level(tracing::Level::DEBUG).with_writer(move || logs.clone()).finish();
+            tracing::subscriber::set_default(subscriber)
+        }
+
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).lines().map(str::to_string).collect()
+        }
+
+        /// The first line containing `needle`, giving tasks a moment to log it
+        async fn wait_for(&self, needle: &str) -> String {
+            for _ in 0..500 {
+                if let Some(line) = self.lines().into_iter().find(|l| l.contains(needle)) {
+                    return line;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("never logged {needle:?}: {:#?}", self.lines());
+        }
+    }
+
+    /// The value logged for `name` in a log line
+    fn field(line: &str, name: &str) -> String {
+        line.split(&format!(" {name}=")).nth(1).unwrap().split(' ').next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn clean_close_is_not_an_error() {
+        let logs = Logs::default();
+        let _guard = logs.capture();
+        let addr = start(DaemonConfig::default()).await;
+        let mut camera = client(addr, &[IAmCamera { road: 1, mile: 0, limit: 60 }.to_msg()]).await;
+        camera.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        camera.read_to_end(&mut rest).await.unwrap();
+
+        let lines = logs.lines();
+        assert!(lines.iter().any(|l| l.contains(" INFO ") && l.contains("client closed connection")), "{lines:#?}");
+        assert!(!lines.iter().any(|l| l.contains(" ERROR ")), "{lines:#?}");
+    }
+
+    #[test]
+    fn too_many_dispatcher_roads_are_capped() {
+        let msg = IAmDispatcher { roads: (0..300).collect() }.to_msg();
+        assert_eq!(msg.len(), 2 + 255 * 2);
+        let (rest, IncomingPacket::IAmDispatcher(parsed)) = parse_incoming(&msg).unwrap() else { panic!("not a dispatcher") };
+        assert!(rest.is_empty());
+        assert_eq!(parsed.roads, (0..255).collect::<Vec<u16>>());
+    }
+
+    #[test]
+    fn long_error_message_framing() {
+        let msg = ErrorMsg::msg(&"x".repeat(300)).to_msg();
+        assert_eq!(msg.len(), 2 + 255);
+        assert_eq!(msg[..2], [0x10, 255]);
+
+        // Cut on a character boundary, with the length byte still matching
+        let msg = ErrorMsg::msg(&"é".repeat(200)).to_msg();
+        assert_eq!(msg[1], 254);
+        assert_eq!(msg.len(), 2 + 254);
+        assert_eq!(std::str::from_utf8(&msg[2..]).unwrap(), "é".repeat(127));
+
+        let plate = [b'A'; 300];
+        assert_eq!(plate.as_slice().to_msg()[0], 255);
+        assert_eq!(plate.as_slice().to_msg().len(), 256);
+    }
+
+    #[tokio::test]
+    async fn queued_tickets_are_all_delivered() {
+        let addr = start(DaemonConfig::default()).await;
+        let plates: Vec<Vec<u8>> = (0..5).map(|i| format!("CAR{i}").into_bytes()).collect();
+        // 120 mph for every car
+        let mut first = vec![IAmCamera { road: 1, mile: 0, limit: 60 }.to_msg()];
+        first.extend(plates.iter().map(|p| report(p, 0)));
+        let mut second = vec![IAmCamera { road: 1, mile: 10, limit: 60 }.to_msg()];
+        second.extend(plates.iter().map(|p| report(p, 300)));
+        let _first = client(addr, &first).await;
+        let _second = client(addr, &second).await;
+
+        let mut dispatcher = client(addr, &[
+            IAmDispatcher { roads: vec![1] }.to_msg(),
+            [0x40, 0, 0, 0, 1].to_vec(),
+        ]).await;
+        let mut received = Vec::new();
+        for _ in &plates {
+            received.push(read_ticket(&mut dispatcher).await.plate);
+        }
+        received.sort_unstable();
+        assert_eq!(received, plates);
+        // Heartbeats on the same socket still arrive once the tickets are out
+        let mut byte = [0u8];
+        dispatcher.read_exact(&mut byte).await.unwrap();
+        assert_eq!(byte, [0x41]);
+    }
+
+    #[tokio::test]
+    async fn burst_of_reports_is_batched() {
+        let logs = Logs::default();
+        let _guard = logs.capture();
+        let addr = start(DaemonConfig::default()).await;
+        let mut messages = vec![IAmCamera { road: 1, mile: 0, limit: 60 }.to_msg()];
+        messages.extend((0..1000).map(|i| report(format!("CAR{i}").as_bytes(), i)));
+        let mut camera = client(addr, &messages).await;
+        camera.shutdown().await.unwrap();
+        camera.read_to_end(&mut Vec::new()).await.unwrap();
+
+        // Each batch is recorded under one lock
+        let batches: Vec<usize> = logs.lines().iter()
+            .filter(|l| l.contains("recording observations"))
+            .map(|l| l.split("reports=").nth(1).unwrap().split(' ').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(batches.iter().sum::<usize>(), 1000);
+        assert!(batches.len() < 20, "{batches:?}");
+        assert!(batches.iter().all(|&b| b <= OBSERVATION_BATCH), "{batches:?}");
+    }
+
+    #[test]
+    fn ticket_callback_fires() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let on_ticket = {
+            let seen = seen.clone();
+            TicketCallback(Arc::new(move |t: &Ticket| seen.lock().unwrap().push((t.plate.clone(), t.road, t.speed))))
+        };
+        ticketed(DaemonConfig { on_ticket: Some(on_ticket), ..DaemonConfig::default() });
+        assert_eq!(*seen.lock().unwrap(), [(b"RE05BKG".to_vec(), 1, 6100)]);
+    }
+
+    #[tokio::test]
+    async fn stress_run() {
+        let addr = start(DaemonConfig::default()).await;
+        let report = stress(addr, 4, 1, 200, Duration::from_millis(500)).await.unwrap();
+        assert!(report.reports_sent > 0);
+        assert!(report.tickets_received > 0);
+        // At most one ticket per car, and each car takes two reports
+        assert!(report.tickets_received <= report.reports_sent / 2, "{report:?}");
+    }
+
+    #[tokio::test]
+    async fn ticket_feed_streams_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let feed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let feed_addr = feed.local_addr().unwrap();
+        tokio::spawn(serve(Listener::from(listener), Daemon::new(DaemonConfig::default(), Some(feed))));
+
+        let mut subscriber = tokio::io::BufReader::new(TcpStream::connect(feed_addr).await.unwrap());
+        subscriber.get_mut().write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            subscriber.read_line(&mut line).await.unwrap();
+        }
+
+        let _first = client(addr, &[IAmCamera { road: 1, mile: 0, limit: 60 }.to_msg(), report(b"UN1X", 0)]).await;
+        let _second = client(addr, &[IAmCamera { road: 1, mile: 61, limit: 60 }.to_msg(), report(b"UN1X", 3600)]).await;
+        line.clear();
+        tokio::time::timeout(Duration::from_secs(5), subscriber.read_line(&mut line)).await.unwrap().unwrap();
+        let ticket: serde_json::Value = serde_json::from_str(line.strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(ticket["plate"], "UN1X");
+        assert_eq!(ticket["road"], 1);
+        assert_eq!(ticket["speed"], 6100);
+        assert_eq!(ticket["mph"], 61.0);
+    }
+
+    #[tokio::test]
+    async fn implausible_timestamps_are_ignored() {
+        let addr = start(DaemonConfig { timestamp_range: Some(1000..=1_000_000), ..DaemonConfig::default() }).await;
+        // Would be ticketed against the report at 3600, if it counted
+        let mut first = client(addr, &[IAmCamera { road: 1, mile: 0, limit: 60 }.to_msg(), report(b"UN1X", 0)]).await;
+        let _second = client(addr, &[IAmCamera { road: 1, mile: 61, limit: 60 }.to_msg(), report(b"UN1X", 3600)]).await;
+        // The camera is still connected, and its later reports count
+        first.write_all(&report(b"UN1X", 7200)).await.unwrap();
+
+        let mut dispatcher = client(addr, &[IAmDispatcher { roads: vec![1] }.to_msg()]).await;
+        let ticket = read_ticket(&mut dispatcher).await;
+        assert_eq!((ticket.timestamp1, ticket.timestamp2), (3600, 7200));
+    }
+
+    #[test]
+    fn round_robin_shares_tickets() {
+        let mut db = Database::new(DaemonConfig { round_robin_dispatch: true, ..DaemonConfig::default() });
+        db.record_speed_limit(1, 60);
+        for car in 0..6 {
+            let plate = format!("CAR{car}").into_bytes();
+            db.record_observations(1, 0, [PlateReport { plate: plate.clone(), timestamp: 0 }]);
+            db.record_observations(1, 100, [PlateReport { plate, timestamp: 3600 }]);
+        }
+        let (first, first_roads) = dispatcher(&mut db, 1);
+        let (second, second_roads) = dispatcher(&mut db, 2);
+
+        // Whichever checks first only gets its share
+        let mut first_count = 0;
+        while db.get_ticket_to_send(first, &first_roads).is_some() {
+            first_count += 1;
+        }
+        let mut second_count = 0;
+        while db.get_ticket_to_send(second, &second_roads).is_some() {
+            second_count += 1;
+        }
+        assert_eq!((first_count, second_count), (3, 3));
+    }
+
+    #[tokio::test]
+    async fn heartbeat_before_identifying() {
+        let addr = start(DaemonConfig::default()).await;
+        let mut unknown = client(addr, &[[0x40, 0, 0, 0, 1].to_vec()]).await;
+        let mut byte = [0u8];
+        unknown.read_exact(&mut byte).await.unwrap();
+        assert_eq!(byte, [0x41]);
+        // Identifying afterwards is still fine
+        unknown.write_all(&IAmDispatcher { roads: vec![1] }.to_msg()).await.unwrap();
+        unknown.read_exact(&mut byte).await.unwrap();
+        assert_eq!(byte, [0x41]);
+
+        let addr = start(DaemonConfig { heartbeat_before_identify: false, ..DaemonConfig::default() }).await;
+        let mut unknown = client(addr, &[[0x40, 0, 0, 0, 1].to_vec()]).await;
+        assert_eq!(read_error(&mut unknown).await, "identify as a camera or dispatcher first");
+    }
+
+    #[tokio::test]
+    async fn plate_before_identifying() {
+        for heartbeat_before_identify in [true, false] {
+            let addr = start(DaemonConfig { heartbeat_before_identify, ..DaemonConfig::default() }).await;
+            let mut unknown = client(addr, &[report(b"UN1X", 0)]).await;
+            assert_eq!(read_error(&mut unknown).await, "wrong client type");
+        }
+    }
+
+    #[test]
+    fn observations_per_plate_road_are_capped() {
+        let mut db = Database::new(DaemonConfig { max_observations_per_plate_road: 5, ..DaemonConfig::default() });
+        db.record_speed_limit(1, 60);
+        // A parked car, reported over and over
+        for timestamp in 0..100 {
+            db.record_observations(1, 0, [PlateReport { plate: b"UN1X".to_vec(), timestamp }]);
+        }
+        let obs = &db.observations[b"UN1X".as_slice()][&1];
+        assert_eq!(obs.iter().map(|o| o.timestamp).collect::<Vec<_>>(), [95, 96, 97, 98, 99]);
+        assert!(db.tickets_to_send.is_empty());
+
+        // Recent history is still there to ticket against
+        db.record_observations(1, 61, [PlateReport { plate: b"UN1X".to_vec(), timestamp: 3699 }]);
+        assert_eq!(db.observations[b"UN1X".as_slice()][&1].len(), 5);
+        let (ticket, _) = &db.tickets_to_send[0];
+        assert_eq!((ticket.timestamp1, ticket.timestamp2, ticket.speed), (99, 3699, 6100));
+    }
+
+    #[test]
+    fn replayed_reports_skip_ticketed_days() {
+        let mut db = ticketed(DaemonConfig::default());
+        let logs = Logs::default();
+        let _guard = logs.capture();
+        // Far over the limit, but on a day that's already been ticketed
+        db.record_observations(1, 200, [PlateReport { plate: b"RE05BKG".to_vec(), timestamp: 7200 }]);
+        assert_eq!(db.tickets_to_send.len(), 1);
+        // Both windows are skipped before their speeds are worked out
+        let lines = logs.lines();
+        assert_eq!(lines.iter().filter(|l| l.contains("already issued ticket on this day")).count(), 2, "{lines:#?}");
+    }
+
+    #[tokio::test]
+    async fn heartbeat_interval_bounds() {
+        let addr = start(DaemonConfig {
+            heartbeat_range: Some(Duration::from_secs(1)..=Duration::from_secs(60)),
+            ..DaemonConfig::default()
+        }).await;
+        for interval in [1u32, 601, u32::MAX] {
+            let mut out_of_range = client(addr, &[[[0x40].as_slice(), &interval.to_be_bytes()].concat()]).await;
+            assert_eq!(read_error(&mut out_of_range).await, "heartbeat interval out of range", "{interval}");
+        }
+
+        let mut lowest = client(addr, &[[0x40, 0, 0, 0, 10].to_vec()]).await;
+        let mut byte = [0u8];
+        lowest.read_exact(&mut byte).await.unwrap();
+        assert_eq!(byte, [0x41]);
+
+        // Asking for no heartbeat is always allowed
+        let mut none = client(addr, &[[0x40, 0, 0, 0, 0].to_vec(), report(b"UN1X", 0)]).await;
+        assert_eq!(read_error(&mut none).await, "wrong client type");
+    }
+
+    #[test]
+    fn pending_ticket_cap() {
+        let pending = |pending_ticket_policy| {
+            let mut db = Database::new(DaemonConfig {
+                max_pending_tickets: NonZeroUsize::new(3),
+                pending_ticket_policy,
+                ..DaemonConfig::default()
+            });
+            db.record_speed_limit(1, 60);
+            for car in 0..5 {
+                let plate = format!("CAR{car}").into_bytes();
+                db.record_observations(1, 0, [PlateReport { plate: plate.clone(), timestamp: 0 }]);
+                db.record_observations(1, 100, [PlateReport { plate, timestamp: 3600 }]);
+            }
+            let plates: Vec<String> = db.tickets_to_send.iter().map(|(t, _)| String::from_utf8_lossy(&t.plate).into_owned()).collect();
+            (plates, db.tickets_issued.len())
+        };
+        assert_eq!(pending(PendingTicketPolicy::DropOldest), (vec!["CAR2".into(), "CAR3".into(), "CAR4".into()], 5));
+        // Cars that weren't ticketed can still be ticketed for that day later
+        assert_eq!(pending(PendingTicketPolicy::StopIssuing), (vec!["CAR0".into(), "CAR1".into(), "CAR2".into()], 3));
+    }
+
+    #[test]
+    fn tickets_counted_per_road() {
+        let mut db = Database::new(DaemonConfig::default());
+        for (road, plate) in [(1, "CAR0"), (1, "CAR1"), (2, "CAR2")] {
+            db.record_speed_limit(road, 60);
+            db.record_observations(road, 0, [PlateReport { plate: plate.into(), timestamp: 0 }]);
+            db.record_observations(road, 100, [PlateReport { plate: plate.into(), timestamp: 3600 }]);
+        }
+        assert_eq!(db.tickets_by_road, HashMap::from([(1, 2), (2, 1)]));
+    }
+
+    #[tokio::test]
+    async fn dispatcher_sending_a_plate() {
+        let logs = Logs::default();
+        let _guard = logs.capture();
+        let addr = start(DaemonConfig::default()).await;
+        let mut dispatcher = client(addr, &[IAmDispatcher { roads: vec![7, 3] }.to_msg(), report(b"UN1X", 0)]).await;
+        assert_eq!(read_error(&mut dispatcher).await, "wrong client type");
+        let lines = logs.lines();
+        assert!(lines.iter().any(|l| l.contains("dispatcher sent a PlateReport") && l.contains("roads=[3, 7]")), "{lines:#?}");
+    }
+
+    fn authenticate(secret: &[u8]) -> Vec<u8> {
+        [[0x90].as_slice(), &secret.to_msg()].concat()
+    }
+
+    #[tokio::test]
+    async fn authentication() {
+        let addr = start(DaemonConfig { auth_secret: Some(b"hunter2".to_vec()), ..DaemonConfig::default() }).await;
+        let _first = client(addr, &[authenticate(b"hunter2"), IAmCamera { road: 1, mile: 0, limit: 60 }.to_msg(), report(b"UN1X", 0)]).await;
+        let _second = client(addr, &[authenticate(b"hunter2"), IAmCamera { road: 1, mile: 61, limit: 60 }.to_msg(), report(b"UN1X", 3600)]).await;
+        let mut dispatcher = client(addr, &[authenticate(b"hunter2"), IAmDispatcher { roads: vec![1] }.to_msg()]).await;
+        assert_eq!(read_ticket(&mut dispatcher).await.speed, 6100);
+
+        let mut missing = client(addr, &[IAmCamera { road: 1, mile: 0, limit: 60 }.to_msg()]).await;
+        assert_eq!(read_error(&mut missing).await, "authenticate first");
+        let mut wrong = client(addr, &[authenticate(b"hunter3")]).await;
+        assert_eq!(read_error(&mut wrong).await, "bad secret");
+
+        // Without a secret the plain protocol doesn't know the message
+        let addr = start(DaemonConfig::default()).await;
+        let mut unexpected = client(addr, &[authenticate(b"hunter2")]).await;
+        assert_eq!(read_error(&mut unexpected).await, "invalid input");
+    }
+
+    #[test]
+    fn observations_as_csv() {
+        let mut db = Database::new(DaemonConfig::default());
+        db.record_speed_limit(1, 60);
+        db.record_speed_limit(2, 60);
+        db.record_observations(2, 5, [PlateReport { plate: b"UN1X".to_vec(), timestamp: 10 }]);
+        db.record_observations(1, 0, [
+            PlateReport { plate: b"UN1X".to_vec(), timestamp: 20 },
+            PlateReport { plate: b"A,\"B\"".to_vec(), timestamp: 30 },
+        ]);
+        let rows = db.observation_rows();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(observations_csv(rows), "plate,road,mile,timestamp\n\"A,\"\"B\"\"\",1,0,30\nUN1X,1,0,20\nUN1X,2,5,10\n");
+    }
+
+    #[tokio::test]
+    async fn disconnect_is_logged_with_stats() {
+        let logs = Logs::default();
+        let _guard = logs.capture();
+        let addr = start(DaemonConfig::default()).await;
+        let camera = IAmCamera { road: 1, mile: 0, limit: 60 }.to_msg();
+        let mut twice = client(addr, &[camera.clone(), camera.clone()]).await;
+        // Reads until the server has closed the connection
+        let error = read_error(&mut twice).await;
+        let mut rest = Vec::new();
+        assert_eq!(twice.read_to_end(&mut rest).await.unwrap(), 0);
+
+        let line = logs.wait_for(" disconnect ").await;
+        assert_eq!(field(&line, "bytes_read"), (camera.len() * 2).to_string());
+        assert_eq!(field(&line, "bytes_written"), (error.len() + 2).to_string());
+        assert_ne!(field(&line, "duration"), "0ns");
+    }
+
+    #[test]
+    fn only_the_default_comparison_uses_the_tolerance() {
+        // 60.05 mph, within the default tolerance of 0.1 mph
+        assert_eq!(ticket_for(LimitComparison::OverTolerance, 6005, 360_000), None);
+        assert_eq!(ticket_for(LimitComparison::Over, 6005, 360_000), Some(6005));
+        assert_eq!(ticket_for(LimitComparison::AtOrOver, 6005, 360_000), Some(6005));
+        // Under the limit is never a ticket
+        assert_eq!(ticket_for(LimitComparison::AtOrOver, 5999, 360_000), None);
+    }
+
+    #[tokio::test]
+    async fn metrics_and_export_from_a_running_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let daemon = Daemon::new(DaemonConfig::default(), None);
+        tokio::spawn(serve(Listener::from(listener), daemon.clone()));
+        assert_eq!(daemon.metrics(), MetricsSnapshot::default());
+
+        let mut dispatcher = client(addr, &[IAmDispatcher { roads: vec![1, 2] }.to_msg()]).await;
+        for (road, plate) in [(1, b"UN1X"), (2, b"RE05")] {
+            let _first = client(addr, &[IAmCamera { road, mile: 0, limit: 60 }.to_msg(), report(plate, 0)]).await;
+            let _second = client(addr, &[IAmCamera { road, mile: 61, limit: 60 }.to_msg(), report(plate, 3600)]).await;
+            assert_eq!(read_ticket(&mut dispatcher).await.road, road);
+        }
+        assert_eq!(daemon.metrics().tickets_by_road, HashMap::from([(1, 1), (2, 1)]));
+
+        let path = std::env::temp_dir().join(format!("speed-daemon-test-{}.csv", std::process::id()));
+        assert_eq!(daemon.export_observations(&path).await.unwrap(), 4);
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(csv, "plate,road,mile,timestamp\nRE05,2,0,0\nRE05,2,61,3600\nUN1X,1,0,0\nUN1X,1,61,3600\n");
+    }
+}
@@ -0,0 +1,159 @@
+//! A load generator that plays the part of cameras and dispatchers against a running server
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use nom::bytes::streaming::tag;
+use nom::IResult;
+use nom::number::streaming::{be_u16, be_u32};
+use nom::sequence::tuple;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::{interval, timeout_at, Instant};
+use tracing::{debug, error, info};
+use common::send;
+use crate::{parse_str, IAmCamera, IAmDispatcher, PlateReport, ToMsg};
+
+/// Speed limit of every synthetic road
+const STRESS_LIMIT: u16 = 60;
+/// Distance between the two cameras on a synthetic road
+const STRESS_MILES: u16 = 10;
+/// Time each synthetic car takes to cover `STRESS_MILES`, which is well over `STRESS_LIMIT`
+const STRESS_SECONDS: u32 = 300;
+/// How long dispatchers keep listening after the cameras stop, since tickets go out once a second
+const STRESS_DRAIN: Duration = Duration::from_secs(2);
+
+/// Totals from a stress run
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct StressReport {
+    pub reports_sent: u64,
+    pub tickets_received: u64,
+}
+
+impl ToMsg for IAmCamera {
+    fn to_msg(&self) -> Vec<u8> {
+        let mut msg = vec![0x80u8];
+        msg.extend_from_slice(&self.road.to_be_bytes());
+        msg.extend_from_slice(&self.mile.to_be_bytes());
+        msg.extend_from_slice(&self.limit.to_be_bytes());
+        msg
+    }
+}
+
+impl ToMsg for IAmDispatcher {
+    fn to_msg(&self) -> Vec<u8> {
+        // The count is a single byte, so never let it disagree with the roads that follow it
+        let count = u8::try_from(self.roads.len()).unwrap_or(u8::MAX);
+        let mut msg = vec![0x81u8, count];
+        for road in &self.roads[..usize::from(count)] {
+            msg.extend_from_slice(&road.to_be_bytes());
+        }
+        msg
+    }
+}
+
+impl ToMsg for PlateReport {
+    fn to_msg(&self) -> Vec<u8> {
+        let mut msg = vec![0x20u8];
+        msg.extend_from_slice(&self.plate.as_slice().to_msg());
+        msg.extend_from_slice(&self.timestamp.to_be_bytes());
+        msg
+    }
+}
+
+/// Skip over a ticket; we only count them
+fn parse_ticket(input: &[u8]) -> IResult<&[u8], ()> {
+    tuple((
+        tag(b"\x21"),
+        parse_str,
+        be_u16,
+        be_u16,
+        be_u32,
+        be_u16,
+        be_u32,
+        be_u16
+    ))(input)
+        .map(|(rest, _)| (rest, ()))
+}
+
+/// Connect `cameras` cameras and `dispatchers` dispatchers to the server at `addr`, and send about
+/// `reports_per_sec` plate reports a second between them for `duration`.
+///
+/// Cameras are paired up on roads, one at each end, and every car is speeding, so each pair of
+/// reports should produce a ticket.
+pub async fn stress(addr: SocketAddr, cameras: usize, dispatchers: usize, reports_per_sec: u32, duration: Duration) -> io::Result<StressReport> {
+    info!(addr=%addr, cameras=cameras, dispatchers=dispatchers, reports_per_sec=reports_per_sec, duration=?duration, "starting stress test");
+    let deadline = Instant::now() + duration;
+    let reports_sent = Arc::new(AtomicU64::new(0));
+    let tickets_received = Arc::new(AtomicU64::new(0));
+    // Dispatchers can only claim so many roads
+    let roads = (cameras.div_ceil(2)).min(u8::MAX as usize) as u16;
+    let period = Duration::from_secs_f64(cameras as f64 / reports_per_sec.max(1) as f64);
+
+    let mut tasks = JoinSet::new();
+    for _ in 0..dispatchers {
+        let stream = TcpStream::connect(addr).await?;
+        tasks.spawn(stress_dispatcher(stream, roads, deadline + STRESS_DRAIN, tickets_received.clone()));
+    }
+    for i in 0..cameras {
+        let stream = TcpStream::connect(addr).await?;
+        let camera = IAmCamera { road: (i / 2) as u16 % roads, mile: (i % 2) as u16 * STRESS_MILES, limit: STRESS_LIMIT };
+        tasks.spawn(stress_camera(stream, camera, period, deadline, reports_sent.clone()));
+    }
+    while let Some(result) = tasks.join_next().await {
+        if let Err(e) = result.unwrap_or_else(|e| Err(io::Error::other(e))) {
+            error!(error=%e, "stress client failed");
+        }
+    }
+
+    let report = StressReport {
+        reports_sent: reports_sent.load(Ordering::Relaxed),
+        tickets_received: tickets_received.load(Ordering::Relaxed),
+    };
+    info!(report=?report, "finished stress test");
+    Ok(report)
+}
+
+async fn stress_camera(mut stream: TcpStream, camera: IAmCamera, period: Duration, deadline: Instant, reports_sent: Arc<AtomicU64>) -> io::Result<()> {
+    send(&mut stream, &camera.to_msg()).await?;
+    let mut ticks = interval(period);
+    // Each car gets its own plate, so none of them hit the one-ticket-per-day rule
+    for car in 0u32.. {
+        if timeout_at(deadline, ticks.tick()).await.is_err() {
+            break;
+        }
+        let timestamp = car + if camera.mile == 0 { 0 } else { STRESS_SECONDS };
+        let report = PlateReport { plate: format!("ST{}R{}", car, camera.road).into_bytes(), timestamp };
+        send(&mut stream, &report.to_msg()).await?;
+        reports_sent.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+async fn stress_dispatcher(mut stream: TcpStream, roads: u16, deadline: Instant, tickets_received: Arc<AtomicU64>) -> io::Result<()> {
+    send(&mut stream, &IAmDispatcher { roads: (0..roads).collect() }.to_msg()).await?;
+    let mut buf = Vec::with_capacity(1024);
+    while let Ok(read) = timeout_at(deadline, stream.read_buf(&mut buf)).await {
+        if read? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed dispatcher connection"));
+        }
+        loop {
+            match parse_ticket(&buf) {
+                Ok((rest, ())) => {
+                    let consumed = buf.len() - rest.len();
+                    buf.drain(..consumed);
+                    tickets_received.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(nom::Err::Incomplete(_)) => break,
+                Err(e) => {
+                    debug!(error=?e, data=?buf, "unexpected message");
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "dispatcher got something other than a ticket"));
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,60 +1,352 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::io;
-use std::net::SocketAddr;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use lru::LruCache;
 use tokio::net::UdpSocket;
 use tracing::{debug, error, warn};
 
+#[derive(Debug, Clone)]
+pub struct DatabaseConfig {
+    /// Largest datagram accepted, in bytes; clients can read this from the `max_packet` key
+    pub max_packet: usize,
+    /// Skip inserts that repeat the last value the same source set for a key within this window
+    pub dedup_window: Option<Duration>,
+    /// Drop datagrams from a source IP that exceeds this rate
+    pub rate_limit: Option<RateLimit>,
+    /// Reply `key=` for keys that aren't set, instead of not replying at all
+    pub reply_on_miss: bool,
+    /// Tell the sender `error=packet too large` when a datagram is over `max_packet`, instead of
+    /// silently dropping it
+    pub reply_oversized: bool,
+    /// Source IPs allowed to retrieve the `dump` key, which replies with every stored pair, one
+    /// datagram each, and to switch compression with `compress=on/off`. Anyone else is ignored.
+    pub admin_sources: Vec<IpAddr>,
+    /// Forward a copy of every accepted insert to this peer, e.g. to keep a standby up to date.
+    /// Copies are sent as `replica=key=value`, which the peer stores but doesn't forward again,
+    /// so replicas pointed at each other in a ring or chain can't loop.
+    pub replicate_to: Option<SocketAddr>,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            max_packet: 1000,
+            dedup_window: None,
+            rate_limit: None,
+            reply_on_miss: false,
+            reply_oversized: false,
+            admin_sources: Vec::new(),
+            replicate_to: None,
+        }
+    }
+}
+
+/// Key a replicated insert arrives under, with the original `key=value` as its value
+const REPLICA_KEY: &[u8] = b"replica";
+
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    /// Sustained datagrams per second allowed from each source IP
+    pub per_second: f64,
+    /// Number of datagrams a source may send in a burst before being limited
+    pub burst: u32,
+    /// Maximum number of source IPs to track; the least recently seen are forgotten first
+    pub max_sources: NonZeroUsize,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Per-source-IP token buckets, bounded by an LRU over source addresses
+struct RateLimiter {
+    limit: RateLimit,
+    buckets: LruCache<IpAddr, TokenBucket>,
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> RateLimiter {
+        let buckets = LruCache::new(limit.max_sources);
+        RateLimiter { limit, buckets }
+    }
+
+    /// Takes a token for `ip`, returning false if it has none left
+    fn allow(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let burst = self.limit.burst as f64;
+        let bucket = self.buckets.get_or_insert_mut(ip, || TokenBucket { tokens: burst, updated: now });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * self.limit.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Remembers the last value each source recently set for each key, so retransmitted inserts
+/// can be skipped without losing a later insert that sets an earlier value again
+struct RecentDatagrams {
+    window: Duration,
+    /// (source, key) -> hash of the last value it set, and when
+    seen: HashMap<(SocketAddr, Vec<u8>), (u64, Instant)>,
+    order: VecDeque<(Instant, SocketAddr, Vec<u8>)>,
+}
+
+impl RecentDatagrams {
+    fn new(window: Duration) -> RecentDatagrams {
+        RecentDatagrams { window, seen: HashMap::new(), order: VecDeque::new() }
+    }
+
+    /// Returns true if `src` set `key` to `value` within the window, and hasn't set it to
+    /// anything else since
+    fn check_duplicate(&mut self, src: SocketAddr, key: &[u8], value: &[u8]) -> bool {
+        let now = Instant::now();
+        while let Some((at, _, _)) = self.order.front() {
+            if now.duration_since(*at) < self.window {
+                break;
+            }
+            let (at, old_src, old_key) = self.order.pop_front().expect("just looked at it");
+            let entry = (old_src, old_key);
+            if self.seen.get(&entry).is_some_and(|&(_, set)| set == at) {
+                self.seen.remove(&entry);
+            }
+        }
+
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let entry = (src, key.to_vec());
+        if self.seen.get(&entry).is_some_and(|&(last, _)| last == hash) {
+            return true;
+        }
+        self.seen.insert(entry, (hash, now));
+        self.order.push_back((now, src, key.to_vec()));
+        false
+    }
+}
+
+/// A stored value, compressed if it was set while compression was on and deflating it helped
+enum Value {
+    Plain(Vec<u8>),
+    Deflated(Vec<u8>),
+}
+
+impl Value {
+    fn deflate(value: Vec<u8>) -> Value {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&value).expect("writing to a Vec can't fail");
+        let compressed = encoder.finish().expect("writing to a Vec can't fail");
+        // Short or random values come out bigger, and aren't worth inflating on every get
+        if compressed.len() < value.len() { Value::Deflated(compressed) } else { Value::Plain(value) }
+    }
+
+    fn bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            Value::Plain(value) => Cow::Borrowed(value),
+            Value::Deflated(compressed) => {
+                let mut value = Vec::new();
+                DeflateDecoder::new(compressed.as_slice()).read_to_end(&mut value)
+                    .expect("we compressed this ourselves");
+                Cow::Owned(value)
+            }
+        }
+    }
+}
+
 struct Database {
-    data: HashMap<Vec<u8>, Vec<u8>>
+    data: HashMap<Vec<u8>, Value>,
+    max_packet: usize,
+    /// Whether new values are stored compressed. This only saves memory on large, repetitive
+    /// values; replies always carry the plain value, so nothing changes on the wire. Admin
+    /// sources switch this with `compress=on/off`.
+    compress: bool,
+    /// Inserts applied since startup, which clients can read from the `stats` key
+    inserts: u64,
 }
 
 impl Database {
-    fn new() -> Database {
-        Database { data: HashMap::new() }
+    fn new(max_packet: usize) -> Database {
+        Database { data: HashMap::new(), max_packet, compress: false, inserts: 0 }
+    }
+
+    /// Read-only keys answered by the server itself, which clients can't set
+    fn reserved(&self, key: &[u8]) -> Option<Cow<'_, [u8]>> {
+        match key {
+            b"version" => Some(Cow::Borrowed(b"Unusual Database Program")),
+            b"ping" => Some(Cow::Borrowed(b"pong")),
+            b"max_packet" => Some(Cow::Owned(self.max_packet.to_string().into_bytes())),
+            b"compress" => Some(Cow::Borrowed(if self.compress { b"on" } else { b"off" })),
+            b"stats" => Some(Cow::Owned(format!("inserts={}", self.inserts).into_bytes())),
+            b"keys" => Some(Cow::Owned(self.key_list(self.max_packet.saturating_sub(b"keys=".len())))),
+            _ => None,
+        }
+    }
+
+    /// Stored keys in order, comma-separated, with as many whole keys as fit in `limit` bytes
+    fn key_list(&self, limit: usize) -> Vec<u8> {
+        let mut keys: Vec<&Vec<u8>> = self.data.keys().collect();
+        keys.sort_unstable();
+        let mut list = Vec::new();
+        for key in keys {
+            let separator = if list.is_empty() { 0 } else { 1 };
+            if list.len() + separator + key.len() > limit {
+                break;
+            }
+            if separator > 0 {
+                list.push(b',');
+            }
+            list.extend_from_slice(key);
+        }
+        list
+    }
+
+    /// Every stored pair in key order as a `key=value` datagram, leaving out any over `max_packet`
+    fn dump(&self) -> Vec<Vec<u8>> {
+        let mut keys: Vec<&Vec<u8>> = self.data.keys().collect();
+        keys.sort_unstable();
+        keys.into_iter()
+            .map(|key| [key.as_slice(), b"=", &self.data[key].bytes()].concat())
+            .filter(|pair| {
+                let fits = pair.len() <= self.max_packet;
+                if !fits {
+                    warn!(bytes=pair.len(), max=self.max_packet, "pair too large to dump, skipping");
+                }
+                fits
+            })
+            .collect()
     }
 
-    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+    /// Returns false if the insert was ignored
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> bool {
         debug!(key=%String::from_utf8_lossy(&key), value=%String::from_utf8_lossy(&value), "set");
-        if key == b"version" {
-            warn!("ignoring set of 'version'");
-            return;
+        if key == b"compress" {
+            match value.as_slice() {
+                b"on" => self.compress = true,
+                b"off" => self.compress = false,
+                _ => {
+                    warn!(value=%String::from_utf8_lossy(&value), "compress must be on or off");
+                    return false;
+                }
+            }
+            return true;
         }
+        if self.reserved(&key).is_some() || key == b"dump" {
+            warn!(key=%String::from_utf8_lossy(&key), "ignoring set of reserved key");
+            return false;
+        }
+        let value = if self.compress { Value::deflate(value) } else { Value::Plain(value) };
         self.data.insert(key, value);
+        self.inserts += 1;
+        true
     }
 
-    fn get<'s>(&'s self, key: &[u8]) -> Option<&'s [u8]> {
+    fn get(&self, key: &[u8]) -> Option<Cow<'_, [u8]>> {
         debug!(key=%String::from_utf8_lossy(key), "get");
-        if key == b"version" {
-            return Some(b"Unusual Database Program");
+        if let Some(value) = self.reserved(key) {
+            return Some(value);
         }
-        return self.data.get(key).map(|s| s.as_slice())
+        self.data.get(key).map(Value::bytes)
     }
 }
 
 
-pub async fn serve(address: SocketAddr) -> io::Result<()> {
-    let sock = UdpSocket::bind(address).await?;
-    let mut buf = vec![0u8; 1024];
-    let mut database = Database::new();
+pub async fn serve(sock: UdpSocket, config: DatabaseConfig) -> io::Result<()> {
+    // One byte spare, so we can tell when a datagram is over the limit
+    let mut buf = vec![0u8; config.max_packet + 1];
+    let mut database = Database::new(config.max_packet);
+    let mut recent = config.dedup_window.map(RecentDatagrams::new);
+    let mut limiter = config.rate_limit.map(RateLimiter::new);
     loop {
-        buf.resize(1024, 0);
+        buf.resize(config.max_packet + 1, 0);
         match sock.recv_from(&mut buf).await {
             Ok((bytes, src)) => {
-                if bytes > 1000 {
-                    error!(bytes=bytes, "too many bytes received");
+                if let Some(ref mut limiter) = limiter {
+                    if !limiter.allow(src.ip()) {
+                        debug!(src=?src, "rate limited, dropping");
+                        continue;
+                    }
+                }
+                if bytes > config.max_packet {
+                    error!(bytes=bytes, max=config.max_packet, "too many bytes received");
+                    if config.reply_oversized {
+                        if let Err(e) = sock.send_to(b"error=packet too large", src).await {
+                            error!(error=?e, "failed to send");
+                        }
+                    }
                     continue;
                 }
                 buf.truncate(bytes);
                 debug!(message=%String::from_utf8_lossy(&buf), src=?src, "message");
 
                 if let Some(equals) = buf.iter().position(|&c| c == b'=') {
-                    let key = buf[..equals].to_vec();
-                    let value = buf[equals+1..].to_vec();
-                    database.set(key, value);
-                } else if let Some(value) = database.get(&buf) {
+                    let mut key = &buf[..equals];
+                    let mut value = &buf[equals+1..];
+                    let replica = key == REPLICA_KEY;
+                    if replica {
+                        let Some(equals) = value.iter().position(|&c| c == b'=') else {
+                            warn!(src=?src, "replicated insert has no key, ignoring");
+                            continue;
+                        };
+                        (key, value) = (&value[..equals], &value[equals+1..]);
+                    }
+                    if key == b"compress" && !config.admin_sources.contains(&src.ip()) {
+                        warn!(src=?src, "compress set by non-admin source, ignoring");
+                        continue;
+                    }
+                    if let Some(ref mut recent) = recent {
+                        if recent.check_duplicate(src, key, value) {
+                            debug!(src=?src, "skipping duplicate insert");
+                            continue;
+                        }
+                    }
+                    let stored = database.set(key.to_vec(), value.to_vec());
+                    match config.replicate_to {
+                        Some(peer) if stored && !replica => {
+                            // Fire and forget; a standby that misses an insert is no worse off
+                            // than one that isn't there
+                            let copy = [REPLICA_KEY, b"=", &buf].concat();
+                            if copy.len() > config.max_packet {
+                                warn!(peer=?peer, bytes=copy.len(), max=config.max_packet, "insert too large to replicate");
+                            } else if let Err(e) = sock.send_to(&copy, peer).await {
+                                warn!(peer=?peer, error=?e, "failed to replicate");
+                            }
+                        }
+                        _ => {}
+                    }
+                } else if buf == b"dump" {
+                    if !config.admin_sources.contains(&src.ip()) {
+                        warn!(src=?src, "dump requested by non-admin source, ignoring");
+                        continue;
+                    }
+                    let pairs = database.dump();
+                    debug!(src=?src, pairs=pairs.len(), "dumping");
+                    for pair in pairs {
+                        if let Err(e) = sock.send_to(&pair, src).await {
+                            error!(error=?e, "failed to send");
+                            break;
+                        }
+                    }
+                } else if let Some(value) = database.get(&buf)
+                    .or_else(|| config.reply_on_miss.then_some(Cow::Borrowed(&b""[..]))) {
                     let mut response = buf.clone();
                     response.push(b'=');
-                    response.extend_from_slice(value);
+                    response.extend_from_slice(&value);
                     match sock.send_to(&response, src).await {
                         Ok(b) => { debug!(length=b, response=%String::from_utf8_lossy(&response), "sent reply") }
                         Err(e) => { error!(error=?e, "failed to send") }
@@ -67,3 +359,263 @@ pub async fn serve(address: SocketAddr) -> io::Result<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn repeated_insert_is_a_duplicate() {
+        let mut recent = RecentDatagrams::new(Duration::from_secs(60));
+        let src = addr("192.0.2.1:5000");
+        assert!(!recent.check_duplicate(src, b"a", b"1"));
+        assert!(recent.check_duplicate(src, b"a", b"1"));
+        assert!(!recent.check_duplicate(addr("192.0.2.2:5000"), b"a", b"1"));
+        assert!(!recent.check_duplicate(src, b"b", b"1"));
+    }
+
+    #[test]
+    fn setting_a_value_again_is_not_a_duplicate() {
+        let mut recent = RecentDatagrams::new(Duration::from_secs(60));
+        let src = addr("192.0.2.1:5000");
+        assert!(!recent.check_duplicate(src, b"a", b"1"));
+        assert!(!recent.check_duplicate(src, b"a", b"2"));
+        assert!(!recent.check_duplicate(src, b"a", b"1"));
+        assert!(recent.check_duplicate(src, b"a", b"1"));
+    }
+
+    #[test]
+    fn duplicates_expire() {
+        let mut recent = RecentDatagrams::new(Duration::ZERO);
+        let src = addr("192.0.2.1:5000");
+        assert!(!recent.check_duplicate(src, b"a", b"1"));
+        assert!(!recent.check_duplicate(src, b"a", b"1"));
+        assert_eq!(recent.seen.len(), 1);
+    }
+
+    async fn start(config: DatabaseConfig) -> SocketAddr {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(serve(server, config));
+        addr
+    }
+
+    /// A client socket for talking to `server`
+    async fn client(server: SocketAddr) -> UdpSocket {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server).await.unwrap();
+        client
+    }
+
+    /// The next datagram, if one arrives soon
+    async fn reply(client: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buf = [0u8; 2000];
+        let bytes = tokio::time::timeout(Duration::from_millis(500), client.recv(&mut buf)).await.ok()?.unwrap();
+        Some(buf[..bytes].to_vec())
+    }
+
+    #[tokio::test]
+    async fn dedup_keeps_the_last_insert() {
+        let client = client(start(DatabaseConfig { dedup_window: Some(Duration::from_secs(60)), ..DatabaseConfig::default() }).await).await;
+        for insert in [&b"a=1"[..], b"a=1", b"a=2", b"a=1", b"a=1"] {
+            client.send(insert).await.unwrap();
+        }
+        client.send(b"a").await.unwrap();
+        assert_eq!(reply(&client).await.as_deref(), Some(&b"a=1"[..]));
+        // Only the inserts that weren't retransmissions were applied
+        client.send(b"stats").await.unwrap();
+        assert_eq!(reply(&client).await.as_deref(), Some(&b"stats=inserts=3"[..]));
+    }
+
+    fn limit(burst: u32, max_sources: usize) -> RateLimit {
+        RateLimit { per_second: 0.001, burst, max_sources: NonZeroUsize::new(max_sources).unwrap() }
+    }
+
+    #[test]
+    fn rate_limit_allows_a_burst_per_source() {
+        let mut limiter = RateLimiter::new(limit(3, 10));
+        let (a, b) = (addr("192.0.2.1:1").ip(), addr("192.0.2.2:1").ip());
+        assert_eq!((0..5).filter(|_| limiter.allow(a)).count(), 3);
+        assert!(limiter.allow(b));
+    }
+
+    #[test]
+    fn rate_limit_forgets_least_recent_sources() {
+        let mut limiter = RateLimiter::new(limit(1, 1));
+        let (a, b) = (addr("192.0.2.1:1").ip(), addr("192.0.2.2:1").ip());
+        assert!(limiter.allow(a));
+        assert!(!limiter.allow(a));
+        assert!(limiter.allow(b));
+        assert_eq!(limiter.buckets.len(), 1);
+        // Forgotten, so it starts again with a full bucket
+        assert!(limiter.allow(a));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_drops_excess_requests() {
+        let client = client(start(DatabaseConfig { rate_limit: Some(limit(5, 10)), ..DatabaseConfig::default() }).await).await;
+        for _ in 0..20 {
+            client.send(b"version").await.unwrap();
+        }
+        let mut replies = 0;
+        while reply(&client).await.is_some() {
+            replies += 1;
+        }
+        assert_eq!(replies, 5);
+    }
+
+    #[tokio::test]
+    async fn reply_on_miss() {
+        let quiet = client(start(DatabaseConfig::default()).await).await;
+        quiet.send(b"missing").await.unwrap();
+        assert_eq!(reply(&quiet).await, None);
+
+        let replying = client(start(DatabaseConfig { reply_on_miss: true, ..DatabaseConfig::default() }).await).await;
+        replying.send(b"missing").await.unwrap();
+        assert_eq!(reply(&replying).await.as_deref(), Some(&b"missing="[..]));
+    }
+
+    #[test]
+    fn ping() {
+        let mut db = Database::new(1000);
+        assert_eq!(db.get(b"ping").as_deref(), Some(&b"pong"[..]));
+        assert!(!db.set(b"ping".to_vec(), b"pang".to_vec()));
+        assert_eq!(db.get(b"ping").as_deref(), Some(&b"pong"[..]));
+    }
+
+    #[tokio::test]
+    async fn max_packet_key() {
+        let client = client(start(DatabaseConfig { max_packet: 500, ..DatabaseConfig::default() }).await).await;
+        client.send(b"max_packet").await.unwrap();
+        assert_eq!(reply(&client).await.as_deref(), Some(&b"max_packet=500"[..]));
+    }
+
+    #[tokio::test]
+    async fn oversized_packets() {
+        let oversized = [b'x'; 101];
+        let quiet = client(start(DatabaseConfig { max_packet: 100, ..DatabaseConfig::default() }).await).await;
+        quiet.send(&oversized).await.unwrap();
+        assert_eq!(reply(&quiet).await, None);
+
+        let replying = client(start(DatabaseConfig { max_packet: 100, reply_oversized: true, ..DatabaseConfig::default() }).await).await;
+        replying.send(&oversized).await.unwrap();
+        assert_eq!(reply(&replying).await.as_deref(), Some(&b"error=packet too large"[..]));
+        // Exactly the limit is fine
+        replying.send(&oversized[..100]).await.unwrap();
+        replying.send(b"version").await.unwrap();
+        assert_eq!(reply(&replying).await.as_deref(), Some(&b"version=Unusual Database Program"[..]));
+    }
+
+    #[test]
+    fn keys_lists_stored_keys() {
+        let mut db = Database::new(1000);
+        for key in ["banana", "apple", "cherry"] {
+            db.set(key.as_bytes().to_vec(), b"1".to_vec());
+        }
+        assert_eq!(db.get(b"keys").as_deref(), Some(&b"apple,banana,cherry"[..]));
+        // Only whole keys that fit
+        assert_eq!(db.key_list(12), b"apple,banana");
+        assert_eq!(db.key_list(11), b"apple");
+    }
+
+    #[test]
+    fn compressed_values_round_trip() {
+        let mut db = Database::new(1000);
+        let value = b"compressible ".repeat(50);
+        assert!(db.set(b"compress".to_vec(), b"on".to_vec()));
+        assert_eq!(db.get(b"compress").as_deref(), Some(&b"on"[..]));
+        db.set(b"text".to_vec(), value.clone());
+        match &db.data[&b"text".to_vec()] {
+            Value::Deflated(compressed) => assert!(compressed.len() < value.len()),
+            Value::Plain(_) => panic!("stored uncompressed"),
+        }
+        assert_eq!(db.get(b"text").as_deref(), Some(value.as_slice()));
+        // Too short to shrink, so it's kept as it is
+        db.set(b"short".to_vec(), b"x".to_vec());
+        assert!(matches!(db.data[&b"short".to_vec()], Value::Plain(_)));
+
+        // Values set while it was on still read back once it's off
+        assert!(db.set(b"compress".to_vec(), b"off".to_vec()));
+        assert_eq!(db.get(b"text").as_deref(), Some(value.as_slice()));
+        assert!(!db.set(b"compress".to_vec(), b"maybe".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn dump_for_admins_only() {
+        let localhost = "127.0.0.1".parse().unwrap();
+        let admin = client(start(DatabaseConfig { admin_sources: vec![localhost], ..DatabaseConfig::default() }).await).await;
+        for insert in [&b"b=2"[..], b"a=1", b"c="] {
+            admin.send(insert).await.unwrap();
+        }
+        admin.send(b"dump").await.unwrap();
+        for pair in [&b"a=1"[..], b"b=2", b"c="] {
+            assert_eq!(reply(&admin).await.as_deref(), Some(pair));
+        }
+        assert_eq!(reply(&admin).await, None);
+
+        let other = client(start(DatabaseConfig::default()).await).await;
+        other.send(b"a=1").await.unwrap();
+        other.send(b"dump").await.unwrap();
+        assert_eq!(reply(&other).await, None);
+    }
+
+    #[tokio::test]
+    async fn compress_for_admins_only() {
+        let localhost = "127.0.0.1".parse().unwrap();
+        let admin = client(start(DatabaseConfig { admin_sources: vec![localhost], ..DatabaseConfig::default() }).await).await;
+        admin.send(b"compress=on").await.unwrap();
+        admin.send(b"compress").await.unwrap();
+        assert_eq!(reply(&admin).await.as_deref(), Some(&b"compress=on"[..]));
+
+        let other = client(start(DatabaseConfig::default()).await).await;
+        other.send(b"compress=on").await.unwrap();
+        other.send(b"compress").await.unwrap();
+        assert_eq!(reply(&other).await.as_deref(), Some(&b"compress=off"[..]));
+    }
+
+    #[tokio::test]
+    async fn inserts_replicate_to_the_standby() {
+        let standby_addr = start(DatabaseConfig::default()).await;
+        let primary = client(start(DatabaseConfig { replicate_to: Some(standby_addr), ..DatabaseConfig::default() }).await).await;
+        primary.send(b"replicated=yes").await.unwrap();
+        primary.send(b"version=fake").await.unwrap();
+        // The primary has handled the insert once it answers this
+        primary.send(b"replicated").await.unwrap();
+        assert_eq!(reply(&primary).await.as_deref(), Some(&b"replicated=yes"[..]));
+
+        let standby = client(standby_addr).await;
+        standby.send(b"replicated").await.unwrap();
+        assert_eq!(reply(&standby).await.as_deref(), Some(&b"replicated=yes"[..]));
+        standby.send(b"version").await.unwrap();
+        assert_eq!(reply(&standby).await.as_deref(), Some(&b"version=Unusual Database Program"[..]));
+    }
+
+    #[tokio::test]
+    async fn replicas_in_a_ring_do_not_loop() {
+        // Three, so a loop would go round even if nothing is sent straight back to its source
+        let mut sockets = Vec::new();
+        for _ in 0..3 {
+            sockets.push(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        }
+        let addrs: Vec<SocketAddr> = sockets.iter().map(|s| s.local_addr().unwrap()).collect();
+        for (i, sock) in sockets.into_iter().enumerate() {
+            let next = addrs[(i + 1) % addrs.len()];
+            tokio::spawn(serve(sock, DatabaseConfig { replicate_to: Some(next), ..DatabaseConfig::default() }));
+        }
+        let first = client(addrs[0]).await;
+        first.send(b"ring=yes").await.unwrap();
+        // Give a copy going round the ring time to show up in the counts
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let expected: [&[u8]; 3] = [b"stats=inserts=1", b"stats=inserts=1", b"stats=inserts=0"];
+        for (addr, expected) in addrs.into_iter().zip(expected) {
+            let replica = client(addr).await;
+            replica.send(b"stats").await.unwrap();
+            assert_eq!(reply(&replica).await.as_deref(), Some(expected), "{addr}");
+        }
+    }
+}